@@ -1,1057 +1,5109 @@
-const CHUNK_SIZE: usize = 64;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+// Tile sizes for the blocked matmul kernels: a KC x NC panel of B and an
+// MC x KC panel of A should stay resident in L2 while C is updated.
+const MC: usize = 64;
+const KC: usize = 256;
+const NC: usize = 512;
+
+// Matmuls with fewer multiply-adds than this run on the calling thread
+const PARALLEL_MIN_WORK: usize = 1 << 16;
+
+// Worker count for the thread pool; 0 means one per available core
+static NUM_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+fn set_num_threads(threads: usize) {
+    println!("Setting thread pool size: {}", threads);
+    NUM_THREADS.store(threads, Ordering::Relaxed);
+}
 
-#[derive(Clone)]
-struct Matrix {
-    rows: usize,
-    cols: usize,
-    data: Vec<[f64; CHUNK_SIZE]>,
+struct ThreadPool {
+    threads: usize,
 }
 
-impl Matrix {
-    fn new(rows: usize, cols: usize) -> Self {
-        println!("Creating new Matrix: {}x{}", rows, cols);
-        let total_elements = rows * cols;
-        let chunks_needed = (total_elements + CHUNK_SIZE - 1) / CHUNK_SIZE;
-        let data = vec![[0.0; CHUNK_SIZE]; chunks_needed];
-        Matrix { rows, cols, data }
+impl ThreadPool {
+    fn new(threads: usize) -> Self {
+        ThreadPool { threads: threads.max(1) }
     }
 
-    fn get(&self, row: usize, col: usize) -> f64 {
-        let index = row * self.cols + col;
-        let chunk = index / CHUNK_SIZE;
-        let offset = index % CHUNK_SIZE;
-        self.data[chunk][offset]
+    fn global() -> Self {
+        match NUM_THREADS.load(Ordering::Relaxed) {
+            0 => ThreadPool::new(thread::available_parallelism().map(|n| n.get()).unwrap_or(1)),
+            threads => ThreadPool::new(threads),
+        }
     }
 
-    fn set(&mut self, row: usize, col: usize, value: f64) {
-        let index = row * self.cols + col;
-        let chunk = index / CHUNK_SIZE;
-        let offset = index % CHUNK_SIZE;
-        self.data[chunk][offset] = value;
+    // Split `data` into contiguous blocks of whole rows and hand each block to
+    // its own worker as f(first_row, block). The split depends only on the
+    // thread count, so results are deterministic for a given pool size.
+    fn for_each_row_block<T, F>(&self, data: &mut [T], row_len: usize, f: F)
+    where
+        T: Send,
+        F: Fn(usize, &mut [T]) + Sync,
+    {
+        let rows = data.len().checked_div(row_len).unwrap_or(0);
+        let workers = self.threads.min(rows);
+        if workers <= 1 {
+            f(0, data);
+            return;
+        }
+        let rows_per_worker = rows.div_ceil(workers);
+        thread::scope(|scope| {
+            for (block, chunk) in data.chunks_mut(rows_per_worker * row_len).enumerate() {
+                let f = &f;
+                scope.spawn(move || f(block * rows_per_worker, chunk));
+            }
+        });
     }
+}
 
-    fn dot(&self, other: &Matrix) -> Matrix {
-        assert!(self.cols == other.rows, "Incompatible matrix dimensions for multiplication");
-        println!("Performing matrix multiplication: {}x{} * {}x{}", self.rows, self.cols, other.rows, other.cols);
-        let rows = self.rows;
-        let cols = other.cols;
-        let mut result = Matrix::new(rows, cols);
+// Element type stored in a Matrix. Arithmetic on the storage-only half types
+// goes through f32, and matmuls widen their operands to `Acc` first so long
+// dot products are not rounded at every step.
+trait Float:
+    Copy
+    + Send
+    + Sync
+    + Default
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Sum
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + 'static
+{
+    type Acc: Float;
+
+    const NAME: &'static str;
+
+    fn from_f64(x: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn widen(self) -> Self::Acc;
+    fn narrow(acc: Self::Acc) -> Self;
+    fn exp(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn is_finite(self) -> bool;
+
+    fn zero() -> Self {
+        Self::from_f64(0.0)
+    }
 
-        for i in 0..rows {
-            for k in 0..self.cols {
-                let self_val = self.get(i, k);
-                for j in 0..cols {
-                    result.set(i, j, result.get(i, j) + self_val * other.get(k, j));
-                }
-            }
-        }
-        result
+    fn one() -> Self {
+        Self::from_f64(1.0)
     }
 
-    fn add(&self, other: &Matrix) -> Matrix {
-        assert!(self.rows == other.rows && self.cols == other.cols, "Incompatible matrix dimensions for addition");
-        println!("Matrix addition: {}x{} + {}x{}", self.rows, self.cols, other.rows, other.cols);
+    fn max(self, other: Self) -> Self {
+        if other > self { other } else { self }
+    }
 
-        let rows = self.rows;
-        let cols = self.cols;
-        let mut result = Matrix::new(rows, cols);
+    // Slice kernels used by the hot loops. f32 and f64 override these with
+    // SIMD versions; the defaults are the scalar reference implementations.
 
-        for i in 0..rows {
-            for j in 0..cols {
-                result.set(i, j, self.get(i, j) + other.get(i, j));
-            }
-        }
-        result
+    // y += alpha * x
+    fn axpy(alpha: Self, x: &[Self], y: &mut [Self]) {
+        scalar_axpy(alpha, x, y);
     }
 
-    fn transpose(&self) -> Matrix {
-        let rows = self.cols;
-        let cols = self.rows;
-        let mut result = Matrix::new(rows, cols);
-        for i in 0..rows {
-            for j in 0..cols {
-                result.set(i, j, self.get(j, i));
-            }
-        }
-        result
+    // ys[r] += alphas[r] * x for four rows sharing one pass over x
+    fn axpy4(alphas: [Self; 4], x: &[Self], ys: [&mut [Self]; 4]) {
+        scalar_axpy4(alphas, x, ys);
     }
 
-    fn subtract(&self, other: &Matrix) -> Matrix {
-        println!("Matrix subtraction: {}x{} - {}x{}", self.rows, self.cols, other.rows, other.cols);
-        assert!(self.rows == other.rows && self.cols == other.cols, "Incompatible matrix dimensions for subtraction");
-        
-        let rows = self.rows;
-        let cols = self.cols;
-        let mut result = Matrix::new(rows, cols);
+    fn dot(x: &[Self], y: &[Self]) -> Self {
+        scalar_dot(x, y)
+    }
 
-        for i in 0..rows {
-            for j in 0..cols {
-                result.set(i, j, self.get(i, j) - other.get(i, j));
-            }
-        }
-        result
+    fn scale_into(alpha: Self, x: &[Self], out: &mut [Self]) {
+        scalar_scale(alpha, x, out);
     }
 
-    fn mul_scalar(&self, scalar: f64) -> Matrix {
-        println!("Scalar multiplication: {}x{} * {}", self.rows, self.cols, scalar);
-        let rows = self.rows;
-        let cols = self.cols;
-        let mut result = Matrix::new(rows, cols);
+    fn reduce_sum(x: &[Self]) -> Self {
+        scalar_sum(x)
+    }
 
-        for i in 0..rows {
-            for j in 0..cols {
-                result.set(i, j, self.get(i, j) * scalar);
-            }
-        }
-        result
+    fn reduce_max(x: &[Self]) -> Self {
+        scalar_max(x)
     }
 
-    fn clone(&self) -> Matrix {
-        Matrix {
-            rows: self.rows,
-            cols: self.cols,
-            data: self.data.clone(),
+    // Sum of (x - mean)^2
+    fn sum_sq_dev(x: &[Self], mean: Self) -> Self {
+        scalar_sum_sq_dev(x, mean)
+    }
+
+    // Run a gemm kernel over slices of this type, widening to Acc and back
+    fn gemm(kernel: GemmKernel<Self::Acc>, dims: GemmDims, a: &[Self], b: &[Self], c: &mut [Self]) {
+        let a: Vec<Self::Acc> = a.iter().map(|&x| x.widen()).collect();
+        let b: Vec<Self::Acc> = b.iter().map(|&x| x.widen()).collect();
+        let mut acc: Vec<Self::Acc> = c.iter().map(|&x| x.widen()).collect();
+        kernel(dims, &a, &b, &mut acc);
+        for (out, value) in c.iter_mut().zip(acc) {
+            *out = Self::narrow(value);
         }
     }
 }
 
+macro_rules! impl_native_float {
+    ($t:ty, $name:expr, $simd:ident) => {
+        impl Float for $t {
+            type Acc = $t;
 
+            const NAME: &'static str = $name;
 
+            fn from_f64(x: f64) -> Self {
+                x as $t
+            }
 
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
 
+            fn widen(self) -> Self {
+                self
+            }
 
+            fn narrow(acc: Self) -> Self {
+                acc
+            }
 
+            fn exp(self) -> Self {
+                <$t>::exp(self)
+            }
 
+            fn sqrt(self) -> Self {
+                <$t>::sqrt(self)
+            }
 
+            fn is_finite(self) -> bool {
+                <$t>::is_finite(self)
+            }
 
+            fn gemm(kernel: GemmKernel<Self>, dims: GemmDims, a: &[Self], b: &[Self], c: &mut [Self]) {
+                kernel(dims, a, b, c);
+            }
 
+            fn axpy(alpha: Self, x: &[Self], y: &mut [Self]) {
+                $simd::axpy(simd_level(), alpha, x, y);
+            }
 
-fn softmax(input: &[f64]) -> Vec<f64> {
-    //println!("Applying softmax to vector of length {}", input.len());
-    let max_val = input.iter().fold(input[0], |a, &b| if a > b { a } else { b });
-    let exp_vals: Vec<f64> = input.iter().map(|&x| (x - max_val).exp()).collect();
-    let sum_exp_vals: f64 = exp_vals.iter().sum();
-    exp_vals.iter().map(|&x| x / sum_exp_vals).collect()
-}
+            fn axpy4(alphas: [Self; 4], x: &[Self], ys: [&mut [Self]; 4]) {
+                $simd::axpy4(simd_level(), alphas, x, ys);
+            }
 
-fn exp(x: f64) -> f64 {
-    if x < -709.0 {
-        return 0.0;
-    }
-    if x > 709.0 {
-        return f64::MAX;
-    }
-    let mut result = 1.0 + x;
-    let x2 = x * x;
-    result += x2 * 0.5;
-    result += x2 * x * 0.1666666666666667;
-    result += x2 * x2 * 0.041666666666666664;
-    result += x2 * x2 * x * 0.008333333333333333;
-    result += x2 * x2 * x2 * 0.001388888888888889;
-    result
-}
+            fn dot(x: &[Self], y: &[Self]) -> Self {
+                $simd::dot(simd_level(), x, y)
+            }
+
+            fn scale_into(alpha: Self, x: &[Self], out: &mut [Self]) {
+                $simd::scale(simd_level(), alpha, x, out);
+            }
+
+            fn reduce_sum(x: &[Self]) -> Self {
+                $simd::sum(simd_level(), x)
+            }
+
+            fn reduce_max(x: &[Self]) -> Self {
+                $simd::max(simd_level(), x)
+            }
 
-fn ln(x: f64) -> f64 {
-    let mut sum = 0.0;
-    let mut term = (x - 1.0) / (x + 1.0);
-    let mut n = 1.0;
-    for _ in 0..100 {
-        sum += term / n;
-        term *= (x - 1.0) * (x - 1.0) / ((x + 1.0) * (x + 1.0));
-        n += 2.0;
-        if term.abs() < 1e-10 {
-            break;
+            fn sum_sq_dev(x: &[Self], mean: Self) -> Self {
+                $simd::sum_sq_dev(simd_level(), x, mean)
+            }
         }
-    }
-    2.0 * sum
+    };
 }
 
-struct Rng {
-    state: u64,
-}
+impl_native_float!(f32, "f32", simd_f32);
+impl_native_float!(f64, "f64", simd_f64);
 
-impl Rng {
-    fn new(seed: u64) -> Self {
-        println!("Initializing RNG with seed: {}", seed);
-        Rng { state: seed }
-    }
+// bfloat16 storage: the top 16 bits of an f32, rounded to nearest even
+#[derive(Clone, Copy, Default)]
+struct Bf16(u16);
 
-    fn next(&mut self) -> u64 {
-        self.state = self.state.wrapping_mul(6364136223846793005).wrapping_add(1);
-        (self.state >> 33) ^ self.state
+impl Bf16 {
+    fn from_f32(x: f32) -> Self {
+        if x.is_nan() {
+            return Bf16(0x7fc0);
+        }
+        let bits = x.to_bits();
+        let rounding = 0x7fff + ((bits >> 16) & 1);
+        Bf16((bits.wrapping_add(rounding) >> 16) as u16)
     }
 
-    fn next_f64(&mut self) -> f64 {
-        self.next() as f64 / u64::max_value() as f64
+    fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
     }
 }
 
-fn initialize_weights(matrix: &mut Matrix, rng: &mut Rng) {
-    println!("Initializing weights for matrix: {}x{}", matrix.rows, matrix.cols);
-    for chunk in matrix.data.iter_mut() {
-        for value in chunk.iter_mut() {
-            *value = rng.next_f64() * 2.0 - 1.0;
+// IEEE 754 binary16 storage, rounded to nearest even
+#[derive(Clone, Copy, Default)]
+struct F16(u16);
+
+impl F16 {
+    fn from_f32(x: f32) -> Self {
+        let bits = x.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exponent = ((bits >> 23) & 0xff) as i32;
+        let mantissa = bits & 0x7f_ffff;
+
+        if exponent == 0xff {
+            // Infinity or NaN
+            let nan_bit = if mantissa != 0 { 0x200 } else { 0 };
+            return F16(sign | 0x7c00 | nan_bit);
+        }
+        let half_exponent = exponent - 127 + 15;
+        if half_exponent >= 0x1f {
+            return F16(sign | 0x7c00);
         }
+        if half_exponent <= 0 {
+            // Subnormal result, or too small to represent at all
+            if half_exponent < -10 {
+                return F16(sign);
+            }
+            let full = mantissa | 0x80_0000;
+            let shift = (14 - half_exponent) as u32;
+            let half_mantissa = full >> shift;
+            let remainder = full & ((1 << shift) - 1);
+            let halfway = 1 << (shift - 1);
+            let round_up = remainder > halfway || (remainder == halfway && half_mantissa & 1 == 1);
+            return F16(sign | (half_mantissa + round_up as u32) as u16);
+        }
+        let half_mantissa = mantissa >> 13;
+        let remainder = mantissa & 0x1fff;
+        let round_up = remainder > 0x1000 || (remainder == 0x1000 && half_mantissa & 1 == 1);
+        // A carry out of the mantissa correctly bumps the exponent
+        let rounded = (((half_exponent as u32) << 10) | half_mantissa) + round_up as u32;
+        F16(sign | rounded as u16)
     }
-}
 
+    fn to_f32(self) -> f32 {
+        let sign = ((self.0 & 0x8000) as u32) << 16;
+        let exponent = ((self.0 >> 10) & 0x1f) as u32;
+        let mantissa = (self.0 & 0x3ff) as u32;
+        match exponent {
+            0 => {
+                let magnitude = mantissa as f32 / 16_777_216.0;
+                if sign != 0 { -magnitude } else { magnitude }
+            }
+            0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+            _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+        }
+    }
+}
 
+macro_rules! impl_half_float {
+    ($t:ident, $name:expr) => {
+        impl Float for $t {
+            type Acc = f32;
 
+            const NAME: &'static str = $name;
 
+            fn from_f64(x: f64) -> Self {
+                $t::from_f32(x as f32)
+            }
 
+            fn to_f64(self) -> f64 {
+                self.to_f32() as f64
+            }
 
+            fn widen(self) -> f32 {
+                self.to_f32()
+            }
 
+            fn narrow(acc: f32) -> Self {
+                $t::from_f32(acc)
+            }
 
+            fn exp(self) -> Self {
+                $t::from_f32(self.to_f32().exp())
+            }
 
+            fn sqrt(self) -> Self {
+                $t::from_f32(self.to_f32().sqrt())
+            }
 
+            fn is_finite(self) -> bool {
+                self.to_f32().is_finite()
+            }
 
+            // Reductions accumulate in f32 and round once at the end; summing
+            // in the storage type would lose most of a long sum
+            fn dot(x: &[Self], y: &[Self]) -> Self {
+                Self::narrow(x.iter().zip(y).map(|(a, b)| a.widen() * b.widen()).sum())
+            }
 
-struct Tokenizer {
-    vocab: Vec<(String, usize)>,
-    word_counts: Vec<(String, usize)>,
-    threshold: usize,
-    max_vocab_size: usize,
-}
+            fn reduce_sum(x: &[Self]) -> Self {
+                Self::narrow(x.iter().map(|v| v.widen()).sum())
+            }
 
-impl Tokenizer {
-    fn new() -> Self {
-        println!("Creating new Tokenizer");
-        Tokenizer {
-            vocab: Vec::new(),
-            word_counts: Vec::new(),
-            threshold: 3,
-            max_vocab_size: 10000,
+            fn sum_sq_dev(x: &[Self], mean: Self) -> Self {
+                let mean = mean.widen();
+                Self::narrow(x.iter().map(|v| (v.widen() - mean) * (v.widen() - mean)).sum())
+            }
         }
-    }
-
-    fn tokenize(&mut self, text: &str) -> Vec<usize> {
-        println!("Tokenizing text of length: {}", text.len());
-        let words: Vec<String> = text.split_whitespace().map(|s| s.to_lowercase()).collect();
 
-        // First pass: count words
-        for word in &words {
-            match self.word_counts.iter_mut().find(|(w, _)| w == word) {
-                Some((_, count)) => *count += 1,
-                None => self.word_counts.push((word.clone(), 1)),
+        impl PartialEq for $t {
+            fn eq(&self, other: &Self) -> bool {
+                self.to_f32() == other.to_f32()
             }
         }
 
-        // Build vocabulary based on frequency
-        self.vocab.clear();
-        self.vocab.push(("<UNK>".to_string(), 0)); // Add unknown token
-        for (word, count) in &self.word_counts {
-            if *count >= self.threshold && self.vocab.len() < self.max_vocab_size {
-                self.vocab.push((word.clone(), self.vocab.len()));
+        impl PartialOrd for $t {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                self.to_f32().partial_cmp(&other.to_f32())
             }
         }
 
-        // Second pass: tokenize
-        let tokens: Vec<usize> = words.iter()
-            .map(|word| self.vocab.iter().position(|(w, _)| w == word).unwrap_or(0))
-            .collect();
-
-        // Print statistics
-        println!("Tokenized into {} tokens", tokens.len());
-        println!("Vocabulary size: {}", self.vocab.len());
-        println!("Total unique words: {}", self.word_counts.len());
-        
-        let words_kept = self.vocab.len() - 1; // Subtract 1 for <UNK> token
-        let words_discarded = self.word_counts.len() - words_kept;
-        println!("Words kept: {}, Words discarded: {}", words_kept, words_discarded);
-        
-        if self.vocab.len() > 1 {
-            println!("Examples of kept words:");
-            for (word, _) in self.vocab.iter().skip(1).take(5) {
-                println!("  - {}", word);
+        impl fmt::Debug for $t {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&self.to_f32(), f)
             }
         }
-        
-        if words_discarded > 0 {
-            println!("Examples of discarded words:");
-            let discarded_words: Vec<_> = self.word_counts.iter()
-                .filter(|(w, _)| !self.vocab.iter().any(|(vw, _)| vw == w))
-                .take(5)
-                .collect();
-            for (word, count) in discarded_words {
-                println!("  - {} (count: {})", word, count);
+
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Display::fmt(&self.to_f32(), f)
             }
         }
 
-        tokens
-    }
-
-    fn vocab_size(&self) -> usize {
-        self.vocab.len()
-    }
-
-    fn decode(&self, token: usize) -> &str {
-        if token >= self.vocab.len() {
-            "<UNK>"
-        } else {
-            &self.vocab[token].0
+        impl Sum for $t {
+            fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+                $t::from_f32(iter.map(|x| x.to_f32()).sum::<f32>())
+            }
         }
-    }
-}
-
-
-
-
-
-
 
+        impl Neg for $t {
+            type Output = Self;
 
-
-
-
-
-struct Embedding {
-    vocab_size: usize,
-    embedding_dim: usize,
-    embeddings: Matrix,
-}
-
-impl Embedding {
-    fn new(vocab_size: usize, embedding_dim: usize) -> Self {
-        println!("Creating Embedding with vocab_size: {}, embedding_dim: {}", vocab_size, embedding_dim);
-        let mut embeddings = Matrix::new(vocab_size, embedding_dim);
-        let mut seed: u64 = 153456759;
-        for i in 0..vocab_size {
-            for j in 0..embedding_dim {
-                embeddings.set(i, j, Self::lcg_random(&mut seed));
+            fn neg(self) -> Self {
+                $t::from_f32(-self.to_f32())
             }
         }
-        Embedding {
-            vocab_size,
-            embedding_dim,
-            embeddings,
+
+        impl_half_float!(@binary $t, Add, add, AddAssign, add_assign, +);
+        impl_half_float!(@binary $t, Sub, sub, SubAssign, sub_assign, -);
+        impl_half_float!(@binary $t, Mul, mul, MulAssign, mul_assign, *);
+        impl_half_float!(@binary $t, Div, div, DivAssign, div_assign, /);
+    };
+    (@binary $t:ident, $op:ident, $method:ident, $op_assign:ident, $method_assign:ident, $sym:tt) => {
+        impl $op for $t {
+            type Output = Self;
+
+            fn $method(self, other: Self) -> Self {
+                $t::from_f32(self.to_f32() $sym other.to_f32())
+            }
         }
-    }
 
-    fn lcg_random(seed: &mut u64) -> f64 {
-        const A: u64 = 1664525;
-        const C: u64 = 1013904223;
-        const M: u64 = 1 << 32;
-        *seed = (A.wrapping_mul(*seed).wrapping_add(C)) % M;
-        (*seed as f64) / (M as f64)
-    }
+        impl $op_assign for $t {
+            fn $method_assign(&mut self, other: Self) {
+                *self = *self $sym other;
+            }
+        }
+    };
+}
 
+impl_half_float!(Bf16, "bf16");
+impl_half_float!(F16, "f16");
+
+// Instruction set used by the slice kernels, picked once at startup from
+// runtime CPU feature detection
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SimdLevel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "x86_64")]
+    Avx512,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
 
-    fn forward(&self, input: Vec<usize>) -> Matrix {
-        println!("Embedding forward pass with input length: {}", input.len());
-        let mut result = Matrix::new(input.len(), self.embedding_dim);
-        for (i, &token) in input.iter().enumerate() {
-            if token >= self.vocab_size {
-                println!("Warning: token {} is out of vocabulary range", token);
-                continue;
+impl SimdLevel {
+    // Every level this CPU can run, starting with the scalar fallback
+    fn available() -> Vec<SimdLevel> {
+        #[allow(unused_mut)]
+        let mut levels = vec![SimdLevel::Scalar];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+                levels.push(SimdLevel::Avx2);
             }
-            for j in 0..self.embedding_dim {
-                result.set(i, j, self.embeddings.get(token, j));
+            if is_x86_feature_detected!("avx512f") {
+                levels.push(SimdLevel::Avx512);
             }
         }
-        result
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                levels.push(SimdLevel::Neon);
+            }
+        }
+        levels
     }
 }
 
+static SIMD_LEVEL: OnceLock<SimdLevel> = OnceLock::new();
+
+// The best available level, unless RUSTFORMER_SIMD=scalar forces the fallback
+fn simd_level() -> SimdLevel {
+    *SIMD_LEVEL.get_or_init(|| {
+        let level = match std::env::var("RUSTFORMER_SIMD").as_deref() {
+            Ok("scalar") => SimdLevel::Scalar,
+            _ => *SimdLevel::available().last().unwrap_or(&SimdLevel::Scalar),
+        };
+        println!("Using SIMD level: {:?}", level);
+        level
+    })
+}
 
-fn positional_encoding(seq_len: usize, embedding_dim: usize) -> Matrix {
-    println!("Generating positional encoding: seq_len={}, embedding_dim={}", seq_len, embedding_dim);
-    let mut encoding = Matrix::new(seq_len, embedding_dim);
-    for pos in 0..seq_len {
-        for i in 0..embedding_dim {
-            if i % 2 == 0 {
-                encoding.set(pos, i, (pos as f64 / 10000_f64.powf(i as f64 / embedding_dim as f64)).sin());
-            } else {
-                encoding.set(pos, i, (pos as f64 / 10000_f64.powf((i - 1) as f64 / embedding_dim as f64)).cos());
-            }
-        }
+fn scalar_axpy<T: Float>(alpha: T, x: &[T], y: &mut [T]) {
+    for (y_val, &x_val) in y.iter_mut().zip(x) {
+        *y_val += alpha * x_val;
     }
-    encoding
 }
 
+fn scalar_axpy4<T: Float>(alphas: [T; 4], x: &[T], ys: [&mut [T]; 4]) {
+    let [y0, y1, y2, y3] = ys;
+    let n = x.len().min(y0.len()).min(y1.len()).min(y2.len()).min(y3.len());
+    for j in 0..n {
+        let x_val = x[j];
+        y0[j] += alphas[0] * x_val;
+        y1[j] += alphas[1] * x_val;
+        y2[j] += alphas[2] * x_val;
+        y3[j] += alphas[3] * x_val;
+    }
+}
 
+fn scalar_dot<T: Float>(x: &[T], y: &[T]) -> T {
+    x.iter().zip(y).fold(T::zero(), |acc, (&a, &b)| acc + a * b)
+}
 
+// No layer needs an out-of-place add or sub yet; the tests still cover them
+#[cfg_attr(not(test), allow(dead_code))]
+fn scalar_add<T: Float>(a: &[T], b: &[T], out: &mut [T]) {
+    for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) {
+        *o = x + y;
+    }
+}
 
+#[cfg_attr(not(test), allow(dead_code))]
+fn scalar_sub<T: Float>(a: &[T], b: &[T], out: &mut [T]) {
+    for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) {
+        *o = x - y;
+    }
+}
 
+fn scalar_scale<T: Float>(alpha: T, x: &[T], out: &mut [T]) {
+    for (o, &x_val) in out.iter_mut().zip(x) {
+        *o = alpha * x_val;
+    }
+}
 
+fn scalar_sum<T: Float>(x: &[T]) -> T {
+    x.iter().fold(T::zero(), |acc, &v| acc + v)
+}
 
+fn scalar_max<T: Float>(x: &[T]) -> T {
+    x.iter().fold(x[0], |acc, &v| acc.max(v))
+}
 
+fn scalar_sum_sq_dev<T: Float>(x: &[T], mean: T) -> T {
+    x.iter().fold(T::zero(), |acc, &v| acc + (v - mean) * (v - mean))
+}
 
+// Vector versions of the slice kernels for one element type and instruction
+// set. Each loop handles whole vectors and finishes the tail in scalar code.
+macro_rules! simd_kernels {
+    (
+        $module:ident, $arch:literal, $feature:literal, $t:ty, $vec:ty, $lanes:literal,
+        load: $load:ident, store: $store:ident, splat: $splat:ident,
+        add: $add:ident, sub: $sub:ident, mul: $mul:ident, max: $max:ident,
+        fma($fa:ident, $fb:ident, $fc:ident) => $fma:expr
+    ) => {
+        #[cfg(target_arch = $arch)]
+        mod $module {
+            #[cfg(target_arch = "aarch64")]
+            use std::arch::aarch64::*;
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::*;
+
+            // fa * fb + fc
+            #[inline]
+            #[target_feature(enable = $feature)]
+            unsafe fn fma($fa: $vec, $fb: $vec, $fc: $vec) -> $vec {
+                $fma
+            }
 
+            #[inline]
+            #[target_feature(enable = $feature)]
+            unsafe fn lanes(v: $vec) -> [$t; $lanes] {
+                let mut out = [0.0; $lanes];
+                $store(out.as_mut_ptr(), v);
+                out
+            }
 
+            #[target_feature(enable = $feature)]
+            pub unsafe fn axpy(alpha: $t, x: &[$t], y: &mut [$t]) {
+                let n = x.len().min(y.len());
+                let a = $splat(alpha);
+                let mut i = 0;
+                while i + $lanes <= n {
+                    let y_vec = $load(y.as_ptr().add(i));
+                    $store(y.as_mut_ptr().add(i), fma(a, $load(x.as_ptr().add(i)), y_vec));
+                    i += $lanes;
+                }
+                for (y_val, &x_val) in y[i..n].iter_mut().zip(&x[i..n]) {
+                    *y_val += alpha * x_val;
+                }
+            }
 
+            #[target_feature(enable = $feature)]
+            pub unsafe fn axpy4(alphas: [$t; 4], x: &[$t], ys: [&mut [$t]; 4]) {
+                let [y0, y1, y2, y3] = ys;
+                let n = x.len().min(y0.len()).min(y1.len()).min(y2.len()).min(y3.len());
+                let a0 = $splat(alphas[0]);
+                let a1 = $splat(alphas[1]);
+                let a2 = $splat(alphas[2]);
+                let a3 = $splat(alphas[3]);
+                let mut i = 0;
+                while i + $lanes <= n {
+                    let x_vec = $load(x.as_ptr().add(i));
+                    $store(y0.as_mut_ptr().add(i), fma(a0, x_vec, $load(y0.as_ptr().add(i))));
+                    $store(y1.as_mut_ptr().add(i), fma(a1, x_vec, $load(y1.as_ptr().add(i))));
+                    $store(y2.as_mut_ptr().add(i), fma(a2, x_vec, $load(y2.as_ptr().add(i))));
+                    $store(y3.as_mut_ptr().add(i), fma(a3, x_vec, $load(y3.as_ptr().add(i))));
+                    i += $lanes;
+                }
+                for j in i..n {
+                    y0[j] += alphas[0] * x[j];
+                    y1[j] += alphas[1] * x[j];
+                    y2[j] += alphas[2] * x[j];
+                    y3[j] += alphas[3] * x[j];
+                }
+            }
 
-struct MultiHeadAttention {
-    heads: usize,
-    dim: usize,
-    head_dim: usize,
-    w_q: Matrix,
-    w_k: Matrix,
-    w_v: Matrix,
-    w_o: Matrix,
-}
+            #[target_feature(enable = $feature)]
+            pub unsafe fn dot(x: &[$t], y: &[$t]) -> $t {
+                let n = x.len().min(y.len());
+                let mut acc = $splat(0.0);
+                let mut i = 0;
+                while i + $lanes <= n {
+                    acc = fma($load(x.as_ptr().add(i)), $load(y.as_ptr().add(i)), acc);
+                    i += $lanes;
+                }
+                let tail: $t = x[i..n].iter().zip(&y[i..n]).map(|(&a, &b)| a * b).sum();
+                lanes(acc).iter().sum::<$t>() + tail
+            }
 
-impl MultiHeadAttention {
-    fn new(heads: usize, dim: usize) -> Self {
-        println!("Creating MultiHeadAttention: heads={}, dim={}", heads, dim);
-        assert!(dim % heads == 0, "dim must be divisible by heads");
-        let head_dim = dim / heads;
-        let w_q = Matrix::new(dim, dim);
-        let w_k = Matrix::new(dim, dim);
-        let w_v = Matrix::new(dim, dim);
-        let w_o = Matrix::new(dim, dim);
-        MultiHeadAttention { heads, dim, head_dim, w_q, w_k, w_v, w_o }
-    }
+            #[cfg_attr(not(test), allow(dead_code))]
+            #[target_feature(enable = $feature)]
+            pub unsafe fn add(a: &[$t], b: &[$t], out: &mut [$t]) {
+                let n = out.len().min(a.len()).min(b.len());
+                let mut i = 0;
+                while i + $lanes <= n {
+                    $store(out.as_mut_ptr().add(i), $add($load(a.as_ptr().add(i)), $load(b.as_ptr().add(i))));
+                    i += $lanes;
+                }
+                for j in i..n {
+                    out[j] = a[j] + b[j];
+                }
+            }
 
-    fn forward(&self, query: &Matrix, key: &Matrix, value: &Matrix) -> Matrix {
-        println!("MultiHeadAttention forward pass");
-        let seq_len = query.rows;
-        
-        // Project inputs to q, k, v
-        let q = query.dot(&self.w_q);
-        let k = key.dot(&self.w_k);
-        let v = value.dot(&self.w_v);
+            #[cfg_attr(not(test), allow(dead_code))]
+            #[target_feature(enable = $feature)]
+            pub unsafe fn sub(a: &[$t], b: &[$t], out: &mut [$t]) {
+                let n = out.len().min(a.len()).min(b.len());
+                let mut i = 0;
+                while i + $lanes <= n {
+                    $store(out.as_mut_ptr().add(i), $sub($load(a.as_ptr().add(i)), $load(b.as_ptr().add(i))));
+                    i += $lanes;
+                }
+                for j in i..n {
+                    out[j] = a[j] - b[j];
+                }
+            }
 
-        let mut concat_output = Matrix::new(seq_len, self.dim);
+            #[target_feature(enable = $feature)]
+            pub unsafe fn scale(alpha: $t, x: &[$t], out: &mut [$t]) {
+                let n = out.len().min(x.len());
+                let a = $splat(alpha);
+                let mut i = 0;
+                while i + $lanes <= n {
+                    $store(out.as_mut_ptr().add(i), $mul(a, $load(x.as_ptr().add(i))));
+                    i += $lanes;
+                }
+                for (o, &x_val) in out[i..n].iter_mut().zip(&x[i..n]) {
+                    *o = alpha * x_val;
+                }
+            }
 
-        for h in 0..self.heads {
-            println!("Processing head {}", h);
-            let start = h * self.head_dim;
-            let end = start + self.head_dim;
-
-            // Compute attention scores
-            let mut attention_scores = Matrix::new(seq_len, seq_len);
-            for i in 0..seq_len {
-                for j in 0..seq_len {
-                    let mut score = 0.0;
-                    for m in start..end {
-                        score += q.get(i, m) * k.get(j, m);
-                    }
-                    attention_scores.set(i, j, score / (self.head_dim as f64).sqrt());
+            #[target_feature(enable = $feature)]
+            pub unsafe fn sum(x: &[$t]) -> $t {
+                let mut acc = $splat(0.0);
+                let mut i = 0;
+                while i + $lanes <= x.len() {
+                    acc = $add(acc, $load(x.as_ptr().add(i)));
+                    i += $lanes;
                 }
+                lanes(acc).iter().sum::<$t>() + x[i..].iter().sum::<$t>()
             }
 
-            // Apply softmax
-            for i in 0..seq_len {
-                let row: Vec<f64> = (0..seq_len).map(|j| attention_scores.get(i, j)).collect();
-                let softmax_row = softmax(&row);
-                for j in 0..seq_len {
-                    attention_scores.set(i, j, softmax_row[j]);
+            #[target_feature(enable = $feature)]
+            pub unsafe fn max(x: &[$t]) -> $t {
+                let mut acc = $splat(x[0]);
+                let mut i = 0;
+                while i + $lanes <= x.len() {
+                    acc = $max(acc, $load(x.as_ptr().add(i)));
+                    i += $lanes;
                 }
+                lanes(acc).iter().chain(&x[i..]).fold(x[0], |m, &v| if v > m { v } else { m })
             }
 
-            // Apply attention to values and directly set to concat_output
-            for i in 0..seq_len {
-                for j in start..end {
-                    let mut sum = 0.0;
-                    for k in 0..seq_len {
-                        sum += attention_scores.get(i, k) * v.get(k, j);
-                    }
-                    concat_output.set(i, j, sum);
+            #[target_feature(enable = $feature)]
+            pub unsafe fn sum_sq_dev(x: &[$t], mean: $t) -> $t {
+                let m = $splat(mean);
+                let mut acc = $splat(0.0);
+                let mut i = 0;
+                while i + $lanes <= x.len() {
+                    let d = $sub($load(x.as_ptr().add(i)), m);
+                    acc = fma(d, d, acc);
+                    i += $lanes;
                 }
+                lanes(acc).iter().sum::<$t>() + x[i..].iter().map(|&v| (v - mean) * (v - mean)).sum::<$t>()
             }
         }
+    };
+}
 
-        println!("MultiHeadAttention output shape: {}x{}", concat_output.rows, concat_output.cols);
-        // Final linear layer
-        concat_output.dot(&self.w_o)
-    }
+simd_kernels!(avx2_f32, "x86_64", "avx2,fma", f32, __m256, 8,
+    load: _mm256_loadu_ps, store: _mm256_storeu_ps, splat: _mm256_set1_ps,
+    add: _mm256_add_ps, sub: _mm256_sub_ps, mul: _mm256_mul_ps, max: _mm256_max_ps,
+    fma(a, b, c) => _mm256_fmadd_ps(a, b, c));
+simd_kernels!(avx2_f64, "x86_64", "avx2,fma", f64, __m256d, 4,
+    load: _mm256_loadu_pd, store: _mm256_storeu_pd, splat: _mm256_set1_pd,
+    add: _mm256_add_pd, sub: _mm256_sub_pd, mul: _mm256_mul_pd, max: _mm256_max_pd,
+    fma(a, b, c) => _mm256_fmadd_pd(a, b, c));
+simd_kernels!(avx512_f32, "x86_64", "avx512f", f32, __m512, 16,
+    load: _mm512_loadu_ps, store: _mm512_storeu_ps, splat: _mm512_set1_ps,
+    add: _mm512_add_ps, sub: _mm512_sub_ps, mul: _mm512_mul_ps, max: _mm512_max_ps,
+    fma(a, b, c) => _mm512_fmadd_ps(a, b, c));
+simd_kernels!(avx512_f64, "x86_64", "avx512f", f64, __m512d, 8,
+    load: _mm512_loadu_pd, store: _mm512_storeu_pd, splat: _mm512_set1_pd,
+    add: _mm512_add_pd, sub: _mm512_sub_pd, mul: _mm512_mul_pd, max: _mm512_max_pd,
+    fma(a, b, c) => _mm512_fmadd_pd(a, b, c));
+simd_kernels!(neon_f32, "aarch64", "neon", f32, float32x4_t, 4,
+    load: vld1q_f32, store: vst1q_f32, splat: vdupq_n_f32,
+    add: vaddq_f32, sub: vsubq_f32, mul: vmulq_f32, max: vmaxq_f32,
+    fma(a, b, c) => vfmaq_f32(c, a, b));
+simd_kernels!(neon_f64, "aarch64", "neon", f64, float64x2_t, 2,
+    load: vld1q_f64, store: vst1q_f64, splat: vdupq_n_f64,
+    add: vaddq_f64, sub: vsubq_f64, mul: vmulq_f64, max: vmaxq_f64,
+    fma(a, b, c) => vfmaq_f64(c, a, b));
+
+// Route a kernel call to the implementation for `level`. Levels are only
+// ever produced by SimdLevel::available, so the CPU supports the chosen one.
+macro_rules! simd_dispatch {
+    ($level:expr, $avx2:ident, $avx512:ident, $neon:ident, $kernel:ident($($arg:expr),*), $fallback:expr) => {
+        match $level {
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx512 => unsafe { $avx512::$kernel($($arg),*) },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 => unsafe { $avx2::$kernel($($arg),*) },
+            #[cfg(target_arch = "aarch64")]
+            SimdLevel::Neon => unsafe { $neon::$kernel($($arg),*) },
+            SimdLevel::Scalar => $fallback,
+        }
+    };
+}
 
+// Per-type entry points taking an explicit level, plus a self-check that
+// compares every kernel at that level against the scalar reference
+macro_rules! simd_entry_points {
+    ($module:ident, $t:ty, $avx2:ident, $avx512:ident, $neon:ident) => {
+        mod $module {
+            use super::*;
 
+            pub fn axpy(level: SimdLevel, alpha: $t, x: &[$t], y: &mut [$t]) {
+                simd_dispatch!(level, $avx2, $avx512, $neon, axpy(alpha, x, y), scalar_axpy(alpha, x, y))
+            }
 
+            pub fn axpy4(level: SimdLevel, alphas: [$t; 4], x: &[$t], ys: [&mut [$t]; 4]) {
+                simd_dispatch!(level, $avx2, $avx512, $neon, axpy4(alphas, x, ys), scalar_axpy4(alphas, x, ys))
+            }
 
+            pub fn dot(level: SimdLevel, x: &[$t], y: &[$t]) -> $t {
+                simd_dispatch!(level, $avx2, $avx512, $neon, dot(x, y), scalar_dot(x, y))
+            }
 
+            #[cfg_attr(not(test), allow(dead_code))]
+            pub fn add(level: SimdLevel, a: &[$t], b: &[$t], out: &mut [$t]) {
+                simd_dispatch!(level, $avx2, $avx512, $neon, add(a, b, out), scalar_add(a, b, out))
+            }
 
+            #[cfg_attr(not(test), allow(dead_code))]
+            pub fn sub(level: SimdLevel, a: &[$t], b: &[$t], out: &mut [$t]) {
+                simd_dispatch!(level, $avx2, $avx512, $neon, sub(a, b, out), scalar_sub(a, b, out))
+            }
 
+            pub fn scale(level: SimdLevel, alpha: $t, x: &[$t], out: &mut [$t]) {
+                simd_dispatch!(level, $avx2, $avx512, $neon, scale(alpha, x, out), scalar_scale(alpha, x, out))
+            }
 
-    fn backward(&mut self, gradients: &Matrix, learning_rate: f64) -> Matrix {
-        println!("MultiHeadAttention backward pass");
-        let seq_len = gradients.rows;
-        
-        // Backpropagate through w_o
-        let d_concat = gradients.dot(&self.w_o.transpose());
-        let mut d_w_o = Matrix::new(self.dim, self.dim);
-        for i in 0..seq_len {
-            for j in 0..self.dim {
-                for k in 0..self.dim {
-                    d_w_o.set(j, k, d_w_o.get(j, k) + gradients.get(i, k) * d_concat.get(i, j));
-                }
+            pub fn sum(level: SimdLevel, x: &[$t]) -> $t {
+                simd_dispatch!(level, $avx2, $avx512, $neon, sum(x), scalar_sum(x))
             }
-        }
-        self.w_o = self.w_o.subtract(&d_w_o.mul_scalar(learning_rate));
 
-        // Split gradients for each head
-        let mut d_heads = Vec::new();
-        for _ in 0..self.heads {
-            d_heads.push(Matrix::new(seq_len, self.head_dim));
-        }
-        for i in 0..seq_len {
-            for h in 0..self.heads {
-                for j in 0..self.head_dim {
-                    d_heads[h].set(i, j, d_concat.get(i, h * self.head_dim + j));
-                }
+            pub fn max(level: SimdLevel, x: &[$t]) -> $t {
+                simd_dispatch!(level, $avx2, $avx512, $neon, max(x), scalar_max(x))
             }
-        }
 
-        // Backpropagate through attention mechanism for each head
-        let mut d_q = Matrix::new(seq_len, self.dim);
-        let mut d_k = Matrix::new(seq_len, self.dim);
-        let mut d_v = Matrix::new(seq_len, self.dim);
+            pub fn sum_sq_dev(level: SimdLevel, x: &[$t], mean: $t) -> $t {
+                simd_dispatch!(level, $avx2, $avx512, $neon, sum_sq_dev(x, mean), scalar_sum_sq_dev(x, mean))
+            }
 
-        for h in 0..self.heads {
-            let d_head = &d_heads[h];
-            
-            // Compute gradients for q, k, v
-            let mut d_q_head = Matrix::new(seq_len, self.head_dim);
-            let mut d_k_head = Matrix::new(seq_len, self.head_dim);
-            let mut d_v_head = Matrix::new(seq_len, self.head_dim);
-
-            for i in 0..seq_len {
-                for j in 0..seq_len {
-                    for k in 0..self.head_dim {
-                        let dq = d_head.get(i, k) * self.w_k.get(h * self.head_dim + k, j) / (self.head_dim as f64).sqrt();
-                        let dk = d_head.get(i, k) * self.w_q.get(h * self.head_dim + k, i) / (self.head_dim as f64).sqrt();
-                        let dv = d_head.get(i, k);
-                        d_q_head.set(i, k, d_q_head.get(i, k) + dq);
-                        d_k_head.set(j, k, d_k_head.get(j, k) + dk);
-                        d_v_head.set(j, k, d_v_head.get(j, k) + dv);
+            // Largest relative error of any kernel at `level` against the
+            // scalar reference, over lengths that exercise the tail loops
+            #[cfg(test)]
+            pub fn check(level: SimdLevel, rng: &mut Rng) -> f64 {
+                let rel_err = |got: $t, want: $t| ((got - want).abs() / want.abs().max(1.0)) as f64;
+                let max_err = |got: &[$t], want: &[$t]| got.iter().zip(want).fold(0.0, |m: f64, (&g, &w)| m.max(rel_err(g, w)));
+                let mut worst: f64 = 0.0;
+                for &len in &[1, 3, 7, 8, 16, 31, 64, 100, 1027] {
+                    let random = |rng: &mut Rng| -> Vec<$t> { (0..len).map(|_| rng.uniform(-1.0, 1.0) as $t).collect() };
+                    let (a, b) = (random(rng), random(rng));
+                    let alpha = rng.next_f64() as $t;
+                    let alphas = [alpha, -alpha, 0.5, 2.0];
+
+                    let (mut got, mut want) = (b.clone(), b.clone());
+                    axpy(level, alpha, &a, &mut got);
+                    scalar_axpy(alpha, &a, &mut want);
+                    worst = worst.max(max_err(&got, &want));
+
+                    let mut got_rows: Vec<Vec<$t>> = (0..4).map(|_| b.clone()).collect();
+                    let mut want_rows = got_rows.clone();
+                    let [g0, g1, g2, g3] = &mut got_rows[..] else { unreachable!() };
+                    axpy4(level, alphas, &a, [g0, g1, g2, g3]);
+                    let [w0, w1, w2, w3] = &mut want_rows[..] else { unreachable!() };
+                    scalar_axpy4(alphas, &a, [w0, w1, w2, w3]);
+                    for (got, want) in got_rows.iter().zip(&want_rows) {
+                        worst = worst.max(max_err(got, want));
                     }
-                }
-            }
 
-            // Accumulate gradients for q, k, v
-            for i in 0..seq_len {
-                for j in 0..self.head_dim {
-                    d_q.set(i, h * self.head_dim + j, d_q_head.get(i, j));
-                    d_k.set(i, h * self.head_dim + j, d_k_head.get(i, j));
-                    d_v.set(i, h * self.head_dim + j, d_v_head.get(i, j));
+                    let (mut got, mut want) = (vec![0.0; len], vec![0.0; len]);
+                    add(level, &a, &b, &mut got);
+                    scalar_add(&a, &b, &mut want);
+                    worst = worst.max(max_err(&got, &want));
+                    sub(level, &a, &b, &mut got);
+                    scalar_sub(&a, &b, &mut want);
+                    worst = worst.max(max_err(&got, &want));
+                    scale(level, alpha, &a, &mut got);
+                    scalar_scale(alpha, &a, &mut want);
+                    worst = worst.max(max_err(&got, &want));
+
+                    worst = worst.max(rel_err(dot(level, &a, &b), scalar_dot(&a, &b)));
+                    worst = worst.max(rel_err(sum(level, &a), scalar_sum(&a)));
+                    worst = worst.max(rel_err(max(level, &a), scalar_max(&a)));
+                    worst = worst.max(rel_err(sum_sq_dev(level, &a, alpha), scalar_sum_sq_dev(&a, alpha)));
                 }
+                worst
             }
         }
+    };
+}
 
-        // Update w_q, w_k, w_v
-        let d_w_q = d_q.transpose().dot(gradients);
-        let d_w_k = d_k.transpose().dot(gradients);
-        let d_w_v = d_v.transpose().dot(gradients);
-
-        self.w_q = self.w_q.subtract(&d_w_q.mul_scalar(learning_rate));
-        self.w_k = self.w_k.subtract(&d_w_k.mul_scalar(learning_rate));
-        self.w_v = self.w_v.subtract(&d_w_v.mul_scalar(learning_rate));
+simd_entry_points!(simd_f32, f32, avx2_f32, avx512_f32, neon_f32);
+simd_entry_points!(simd_f64, f64, avx2_f64, avx512_f64, neon_f64);
+
+// Errors from shape-checked matrix operations
+#[derive(Debug, Clone, PartialEq)]
+enum TensorError {
+    ShapeMismatch { op: &'static str, left: Vec<usize>, right: Vec<usize> },
+    OutOfBounds { index: Vec<usize>, shape: Vec<usize> },
+    NonFinite { context: &'static str, index: Vec<usize> },
+    MissingCache { layer: &'static str },
+    NonFiniteGradient { param: String, index: Vec<usize> },
+    InvalidAxes { op: &'static str, axes: Vec<usize>, shape: Vec<usize> },
+    InvalidSlice { axis: usize, start: usize, end: usize, shape: Vec<usize> },
+    NonContiguous { op: &'static str, shape: Vec<usize>, strides: Vec<usize> },
+}
 
-        // Return gradients for the input
-        d_q.add(&d_k).add(&d_v)
+impl fmt::Display for TensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TensorError::ShapeMismatch { op, left, right } => write!(f, "Incompatible shapes for {}: {:?} and {:?}", op, left, right),
+            TensorError::OutOfBounds { index, shape } => write!(f, "Index {:?} out of bounds for shape {:?}", index, shape),
+            TensorError::NonFinite { context, index } => write!(f, "Non-finite value in {} at {:?}", context, index),
+            TensorError::MissingCache { layer } => write!(f, "{} backward called without a cached forward pass", layer),
+            TensorError::NonFiniteGradient { param, index } => write!(f, "Non-finite gradient for {} at {:?}", param, index),
+            TensorError::InvalidAxes { op, axes, shape } => write!(f, "Invalid axes {:?} for {} of shape {:?}", axes, op, shape),
+            TensorError::InvalidSlice { axis, start, end, shape } => write!(f, "Slice {}..{} out of bounds for axis {} of shape {:?}", start, end, axis, shape),
+            TensorError::NonContiguous { op, shape, strides } => write!(f, "Cannot {} a non-contiguous view of shape {:?} with strides {:?}", op, shape, strides),
+        }
     }
 }
 
+impl std::error::Error for TensorError {}
 
+// Text that should describe a saved or configured value but does not
+#[derive(Debug)]
+struct ParseError {
+    what: &'static str,
+    input: String,
+    reason: String,
+}
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not parse {} '{}': {}", self.what, self.input, self.reason)
+    }
+}
 
+impl std::error::Error for ParseError {}
 
+// Number of matrix buffers allocated so far, for per-step reporting
+static MATRIX_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
 
+fn matrix_allocations() -> usize {
+    MATRIX_ALLOCATIONS.load(Ordering::Relaxed)
+}
 
+struct Matrix<T> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
+}
 
+impl<T: Clone> Clone for Matrix<T> {
+    fn clone(&self) -> Self {
+        MATRIX_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        Matrix { rows: self.rows, cols: self.cols, data: self.data.clone() }
+    }
+}
 
+impl<T: Float> Matrix<T> {
+    fn new(rows: usize, cols: usize) -> Self {
+        println!("Creating new Matrix: {}x{}", rows, cols);
+        MATRIX_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let data = vec![T::zero(); rows * cols];
+        Matrix { rows, cols, data }
+    }
 
+    fn shape(&self) -> Vec<usize> {
+        vec![self.rows, self.cols]
+    }
 
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn get(&self, row: usize, col: usize) -> T {
+        assert!(row < self.rows && col < self.cols, "Index ({}, {}) out of bounds for {}x{} matrix", row, col, self.rows, self.cols);
+        self.data[row * self.cols + col]
+    }
 
+    fn set(&mut self, row: usize, col: usize, value: T) {
+        assert!(row < self.rows && col < self.cols, "Index ({}, {}) out of bounds for {}x{} matrix", row, col, self.rows, self.cols);
+        self.data[row * self.cols + col] = value;
+    }
 
+    fn check_same_shape(&self, op: &'static str, other: &Matrix<T>) -> Result<(), TensorError> {
+        if self.rows == other.rows && self.cols == other.cols {
+            Ok(())
+        } else {
+            Err(TensorError::ShapeMismatch { op, left: self.shape(), right: other.shape() })
+        }
+    }
 
+    // Fail on the first NaN or infinity, reporting where it was found
+    fn check_finite(&self, context: &'static str) -> Result<(), TensorError> {
+        match self.data.iter().position(|value| !value.is_finite()) {
+            Some(index) => Err(TensorError::NonFinite { context, index: vec![index / self.cols, index % self.cols] }),
+            None => Ok(()),
+        }
+    }
 
+    fn view(&self) -> TensorView<'_, T> {
+        TensorView { data: &self.data, shape: vec![self.rows, self.cols], strides: vec![self.cols, 1], offset: 0 }
+    }
 
+    fn row(&self, row: usize) -> &[T] {
+        &self.data[row * self.cols..(row + 1) * self.cols]
+    }
 
+    fn row_mut(&mut self, row: usize) -> &mut [T] {
+        &mut self.data[row * self.cols..(row + 1) * self.cols]
+    }
 
-struct FeedForward {
-    input_dim: usize,
-    output_dim: usize,
-    w1: Matrix,
-    w2: Matrix,
-    b1: Vec<f64>,
-    b2: Vec<f64>,
-}
-
-impl FeedForward {
-    fn new(input_dim: usize, output_dim: usize) -> Self {
-        println!("Creating FeedForward: input_dim={}, output_dim={}", input_dim, output_dim);
-        let w1 = Matrix::new(input_dim, input_dim * 4);
-        let w2 = Matrix::new(input_dim * 4, output_dim);
-        let b1 = vec![0.0; input_dim * 4];
-        let b2 = vec![0.0; output_dim];
-        FeedForward { input_dim, output_dim, w1, w2, b1, b2 }
+    // Fill self with the column range of source starting at start
+    #[cfg(test)]
+    fn copy_columns(&mut self, source: &Matrix<T>, start: usize) {
+        for i in 0..self.rows {
+            let cols = self.cols;
+            self.row_mut(i).copy_from_slice(&source.row(i)[start..start + cols]);
+        }
     }
 
+    // out (1 x cols) += the sum of every row, as for a bias gradient
+    fn sum_rows_into(&self, out: &mut Matrix<T>) {
+        for i in 0..self.rows {
+            T::axpy(T::one(), self.row(i), out.row_mut(0));
+        }
+    }
 
-    fn backward(&mut self, gradients: &Matrix, learning_rate: f64) -> Matrix {
-        println!("FeedForward backward pass");
-        let mut hidden_gradients = Matrix::new(gradients.rows, self.input_dim * 4);
-        let mut input_gradients = Matrix::new(gradients.rows, self.input_dim);
-
-        // Backpropagate through second layer
-        for i in 0..gradients.rows {
-            for j in 0..self.input_dim * 4 {
-                for k in 0..self.output_dim {
-                    hidden_gradients.set(i, j, hidden_gradients.get(i, j) + gradients.get(i, k) * self.w2.get(j, k));
-                    self.w2.set(j, k, self.w2.get(j, k) - learning_rate * gradients.get(i, k) * hidden_gradients.get(i, j));
-                }
-            }
+    #[cfg(test)]
+    fn set_columns(&mut self, start: usize, source: &Matrix<T>) {
+        for i in 0..self.rows {
+            self.row_mut(i)[start..start + source.cols].copy_from_slice(source.row(i));
         }
+    }
 
-        // Update b2
-        for k in 0..self.output_dim {
-            for i in 0..gradients.rows {
-                self.b2[k] -= learning_rate * gradients.get(i, k);
-            }
+    // self += op(a) * op(b), accumulating into the existing buffer
+    fn try_gemm(&mut self, op: GemmOp, a: &Matrix<T>, b: &Matrix<T>) -> Result<(), TensorError> {
+        let ((m, k), (k2, n)) = match op {
+            GemmOp::NN => ((a.rows, a.cols), (b.rows, b.cols)),
+            GemmOp::NT => ((a.rows, a.cols), (b.cols, b.rows)),
+            GemmOp::TN => ((a.cols, a.rows), (b.rows, b.cols)),
+        };
+        if k != k2 {
+            return Err(TensorError::ShapeMismatch { op: "multiplication", left: a.shape(), right: b.shape() });
+        }
+        if self.rows != m || self.cols != n {
+            return Err(TensorError::ShapeMismatch { op: "gemm output", left: self.shape(), right: vec![m, n] });
         }
+        println!("Performing {:?} matrix multiplication: {}x{} * {}x{} into {}x{}", op, a.rows, a.cols, b.rows, b.cols, m, n);
+        parallel_gemm(op, m, n, k, &a.data, &b.data, &mut self.data);
+        Ok(())
+    }
 
-        // Backpropagate through first layer and ReLU
-        for i in 0..gradients.rows {
-            for j in 0..self.input_dim {
-                for k in 0..self.input_dim * 4 {
-                let hidden_grad = hidden_gradients.get(i, k);
-                if hidden_grad > 0.0 {
-                    let input_grad = hidden_grad * self.w1.get(j, k);
-                    input_gradients.set(i, j, input_gradients.get(i, j) + input_grad);
-                    self.w1.set(j, k, self.w1.get(j, k) - learning_rate * hidden_grad * gradients.get(i, j));
+    fn try_add_assign(&mut self, other: &Matrix<T>) -> Result<(), TensorError> {
+        self.check_same_shape("addition", other)?;
+        T::axpy(T::one(), &other.data, &mut self.data);
+        Ok(())
+    }
 
-                }
-                }
-                self.b1[j] -= learning_rate * hidden_gradients.get(i, j);
-            }
+    // self += alpha * x
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn try_axpy(&mut self, alpha: T, x: &Matrix<T>) -> Result<(), TensorError> {
+        self.check_same_shape("axpy", x)?;
+        T::axpy(alpha, &x.data, &mut self.data);
+        Ok(())
+    }
+
+    fn scale_inplace(&mut self, scalar: T) {
+        for value in &mut self.data {
+            *value *= scalar;
         }
+    }
+}
+
+// A trainable matrix and the gradient backward accumulates for it
+struct Param<T> {
+    value: Matrix<T>,
+    grad: Matrix<T>,
+}
 
-        input_gradients
+impl<T: Float> Param<T> {
+    fn new(rows: usize, cols: usize) -> Self {
+        Param { value: Matrix::new(rows, cols), grad: Matrix::new(rows, cols) }
     }
 
+    fn zero_grad(&mut self) {
+        self.grad.data.fill(T::zero());
+    }
+}
 
+// Interface shared by every layer and the model. In training mode forward
+// keeps the state backward needs; in eval mode it caches nothing.
+trait Module<T: Float> {
+    type Input: ?Sized;
+    type InputGrad;
 
-    fn forward(&self, input: &Matrix) -> Matrix {
-        println!("FeedForward forward pass");
-        let mut hidden = Matrix::new(input.rows, self.input_dim * 4);
-        for i in 0..input.rows {
-            for j in 0..self.input_dim * 4 {
-                hidden.set(i, j, self.b1[j]);
-                for k in 0..self.input_dim {
-                    hidden.set(i, j, hidden.get(i, j) + input.get(i, k) * self.w1.get(k, j));
-                }
-                hidden.set(i, j, hidden.get(i, j).max(0.0)); // ReLU activation
-            }
-        }
+    fn forward(&mut self, input: &Self::Input, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError>;
 
-        let mut output = Matrix::new(input.rows, self.output_dim);
-        for i in 0..input.rows {
-            for j in 0..self.output_dim {
-                output.set(i, j, self.b2[j]);
-                for k in 0..self.input_dim * 4 {
-                    output.set(i, j, output.get(i, j) + hidden.get(i, k) * self.w2.get(k, j));
-                }
-            }
+    // Accumulate parameter gradients from the gradient of the last forward
+    // output, and return the gradient for its input
+    fn backward(&mut self, gradients: &Matrix<T>, ws: &Workspace<T>) -> Result<Self::InputGrad, TensorError>;
+
+    // Parameters under dotted paths such as blocks.0.attention.w_q, in the
+    // order forward applies them; both methods list the same paths in the same order
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)>;
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)>;
+
+    fn set_training(&mut self, training: bool);
+    fn is_training(&self) -> bool;
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn parameters_mut(&mut self) -> Vec<&mut Matrix<T>> {
+        self.named_parameters_mut().into_iter().map(|(_, param)| &mut param.value).collect()
+    }
+
+    fn zero_grad(&mut self) {
+        for (_, param) in self.named_parameters_mut() {
+            param.zero_grad();
         }
-        println!("FeedForward output shape: {}x{}", output.rows, output.cols);
-        output
     }
 
+    fn parameter_count(&self) -> usize {
+        self.named_parameters().iter().map(|(_, param)| param.value.data.len()).sum()
+    }
+}
 
+// Prefix the names of a child module's parameters with its field name
+fn nested<P>(prefix: &str, params: Vec<(String, P)>) -> Vec<(String, P)> {
+    params.into_iter().map(|(name, param)| (format!("{}.{}", prefix, name), param)).collect()
 }
 
-struct LayerNorm {
-    dim: usize,
-    gamma: Vec<f64>,
-    beta: Vec<f64>,
+// Pool of scratch matrices that layers borrow from and hand back, so a
+// forward pass reuses the same buffers across steps and generation calls
+struct Workspace<T> {
+    state: Mutex<WorkspaceState<T>>,
 }
 
-impl LayerNorm {
-    fn new(dim: usize) -> Self {
-        println!("Creating LayerNorm: dim={}", dim);
-        let gamma = vec![1.0; dim];
-        let beta = vec![0.0; dim];
-        LayerNorm { dim, gamma, beta }
+struct WorkspaceState<T> {
+    free: Vec<Vec<T>>,
+    in_use_bytes: usize,
+    peak_bytes: usize,
+}
+
+impl<T: Float> Workspace<T> {
+    fn new() -> Self {
+        Workspace { state: Mutex::new(WorkspaceState { free: Vec::new(), in_use_bytes: 0, peak_bytes: 0 }) }
+    }
+
+    // Preallocate one buffer per shape so the first pass doesn't allocate
+    fn reserve(&self, shapes: &[(usize, usize)]) {
+        println!("Reserving workspace for {} buffers", shapes.len());
+        let buffers: Vec<Matrix<T>> = shapes.iter().map(|&(rows, cols)| self.take(rows, cols)).collect();
+        for buffer in buffers {
+            self.give(buffer);
+        }
     }
 
+    // Borrow a zeroed rows x cols matrix, reusing the smallest pooled buffer
+    // that fits
+    fn take(&self, rows: usize, cols: usize) -> Matrix<T> {
+        let len = rows * cols;
+        let mut state = self.state.lock().unwrap();
+        let best = state.free.iter().enumerate()
+            .filter(|(_, buffer)| buffer.capacity() >= len)
+            .min_by_key(|(_, buffer)| buffer.capacity())
+            .map(|(index, _)| index);
+        let matrix = match best {
+            Some(index) => {
+                let mut data = state.free.swap_remove(index);
+                data.clear();
+                data.resize(len, T::zero());
+                Matrix { rows, cols, data }
+            }
+            None => Matrix::new(rows, cols),
+        };
+        state.in_use_bytes += matrix.data.capacity() * std::mem::size_of::<T>();
+        state.peak_bytes = state.peak_bytes.max(state.in_use_bytes);
+        matrix
+    }
 
+    // Return a borrowed matrix to the pool
+    fn give(&self, matrix: Matrix<T>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use_bytes = state.in_use_bytes.saturating_sub(matrix.data.capacity() * std::mem::size_of::<T>());
+        state.free.push(matrix.data);
+    }
 
+    // Borrow a zeroed tensor from the same pool as the matrices
+    fn take_tensor(&self, shape: &[usize]) -> Tensor<T> {
+        let Matrix { data, .. } = self.take(1, shape.iter().product());
+        Tensor { data, shape: shape.to_vec() }
+    }
 
-    fn backward(&mut self, gradients: &Matrix, learning_rate: f64) -> Matrix {
-        println!("LayerNorm backward pass");
-        let mut d_input = Matrix::new(gradients.rows, gradients.cols);
-        let mut d_gamma = vec![0.0; self.dim];
-        let mut d_beta = vec![0.0; self.dim];
+    fn give_tensor(&self, tensor: Tensor<T>) {
+        self.give(tensor.into_matrix());
+    }
 
-        for i in 0..gradients.rows {
-            let mean: f64 = (0..gradients.cols).map(|j| gradients.get(i, j)).sum::<f64>() / gradients.cols as f64;
-            let variance: f64 = (0..self.dim).map(|j| (gradients.get(i, j) - mean).powi(2)).sum::<f64>() / self.dim as f64 + 1e-6;
+    // op(a) * op(b) into a borrowed buffer
+    fn gemm(&self, op: GemmOp, a: &Matrix<T>, b: &Matrix<T>) -> Result<Matrix<T>, TensorError> {
+        let (rows, cols) = match op {
+            GemmOp::NN => (a.rows, b.cols),
+            GemmOp::NT => (a.rows, b.rows),
+            GemmOp::TN => (a.cols, b.cols),
+        };
+        let mut result = self.take(rows, cols);
+        result.try_gemm(op, a, b)?;
+        Ok(result)
+    }
 
-            let std_dev = (variance + 1e-6).sqrt();
+    fn report(&self) {
+        let state = self.state.lock().unwrap();
+        let free_bytes: usize = state.free.iter().map(|buffer| buffer.capacity() * std::mem::size_of::<T>()).sum();
+        println!("Workspace: peak {:.1} KiB in use, {:.1} KiB in {} free buffers",
+            state.peak_bytes as f64 / 1024.0, free_bytes as f64 / 1024.0, state.free.len());
+    }
+}
 
-            for j in 0..gradients.cols {
-                let x_centered = gradients.get(i, j) - mean;
-                let x_norm = x_centered / std_dev;
+// Problem size for a gemm kernel: C is m x n, the inner dimension is k, and
+// lda is the row stride of A as stored
+#[derive(Clone, Copy)]
+struct GemmDims {
+    m: usize,
+    n: usize,
+    k: usize,
+    lda: usize,
+}
 
-                if j < self.dim {
-                    d_gamma[j] += gradients.get(i, j) * x_norm;
-                    d_beta[j] += gradients.get(i, j);
-                }
+// Signature shared by the gemm kernels: (dims, a, b, c)
+type GemmKernel<T> = fn(GemmDims, &[T], &[T], &mut [T]);
 
-                d_input.set(i, j, if j < self.gamma.len() {
-                    self.gamma[j] * (gradients.get(i, j) - (d_beta[j] + x_norm * d_gamma[j]) / gradients.cols as f64) / std_dev
-                } else {
-                    gradients.get(i, j)
-                });
-            }
-        }
+// Which operand of C += op(A) * op(B) is read transposed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GemmOp {
+    NN,
+    NT,
+    TN,
+}
 
-        // Update gamma and beta
-        for j in 0..self.dim {
-            self.gamma[j] -= learning_rate * d_gamma[j];
-            self.beta[j] -= learning_rate * d_beta[j];
+impl GemmOp {
+    // The kernel for this layout and the row stride of A it reads
+    fn kernel<T: Float>(self, m: usize, k: usize) -> (GemmKernel<T>, usize) {
+        match self {
+            GemmOp::NN => (gemm_nn::<T>, k),
+            GemmOp::NT => (gemm_nt::<T>, k),
+            GemmOp::TN => (gemm_tn::<T>, m),
         }
-
-        d_input
     }
+}
 
+// Run a gemm kernel with the rows of C (and the matching rows, or columns for
+// TN, of A) split across the thread pool
+fn parallel_gemm<T: Float>(op: GemmOp, m: usize, n: usize, k: usize, a: &[T], b: &[T], c: &mut [T]) {
+    let (kernel, lda) = op.kernel::<T::Acc>(m, k);
+    if m * n * k < PARALLEL_MIN_WORK {
+        T::gemm(kernel, GemmDims { m, n, k, lda }, a, b, c);
+        return;
+    }
+    ThreadPool::global().for_each_row_block(c, n, |first_row, c_block| {
+        let rows = c_block.len() / n;
+        let a_block = match op {
+            GemmOp::TN => &a[first_row..(k - 1) * m + first_row + rows],
+            _ => &a[first_row * k..(first_row + rows) * k],
+        };
+        T::gemm(kernel, GemmDims { m: rows, n, k, lda }, a_block, b, c_block);
+    });
+}
 
-
-
-    fn forward(&self, input: &Matrix) -> Matrix {
-        println!("LayerNorm forward pass");
-        let mut normed = Matrix::new(input.rows, self.dim);
-        for i in 0..input.rows {
-            let mean: f64 = (0..self.dim).map(|j| input.get(i, j)).sum::<f64>() / self.dim as f64;
-            let variance: f64 = (0..self.dim).map(|j| (input.get(i, j) - mean).powi(2)).sum::<f64>() / self.dim as f64;
-            for j in 0..self.dim {
-                if j >= self.gamma.len() || j >= self.beta.len() {
-                    println!("Warning: j={} is out of bounds for gamma/beta (len={})", j, self.gamma.len());
-                    continue;
+// C += A * B for row-major A (m x k), B (k x n) and C (m x n).
+// Loops are tiled so panels of A and B stay in cache, and four rows of C are
+// updated per pass over a row of B with the axpy4 kernel.
+fn gemm_nn<T: Float>(GemmDims { m, n, k, .. }: GemmDims, a: &[T], b: &[T], c: &mut [T]) {
+    for jc in (0..n).step_by(NC) {
+        let nb = NC.min(n - jc);
+        for pc in (0..k).step_by(KC) {
+            let kb = KC.min(k - pc);
+            for ic in (0..m).step_by(MC) {
+                let i_end = (ic + MC).min(m);
+                let mut i = ic;
+                while i + 4 <= i_end {
+                    let (c0, rest) = c[i * n..(i + 4) * n].split_at_mut(n);
+                    let (c1, rest) = rest.split_at_mut(n);
+                    let (c2, c3) = rest.split_at_mut(n);
+                    let c0 = &mut c0[jc..jc + nb];
+                    let c1 = &mut c1[jc..jc + nb];
+                    let c2 = &mut c2[jc..jc + nb];
+                    let c3 = &mut c3[jc..jc + nb];
+                    for p in pc..pc + kb {
+                        let alphas = [a[i * k + p], a[(i + 1) * k + p], a[(i + 2) * k + p], a[(i + 3) * k + p]];
+                        let b_row = &b[p * n + jc..p * n + jc + nb];
+                        T::axpy4(alphas, b_row, [&mut *c0, &mut *c1, &mut *c2, &mut *c3]);
+                    }
+                    i += 4;
+                }
+                for i in i..i_end {
+                    let c_row = &mut c[i * n + jc..i * n + jc + nb];
+                    for p in pc..pc + kb {
+                        T::axpy(a[i * k + p], &b[p * n + jc..p * n + jc + nb], c_row);
+                    }
                 }
-                normed.set(i, j, self.gamma[j] * (input.get(i, j) - mean) / (variance + 1e-6).sqrt() + self.beta[j]);
             }
         }
-        println!("LayerNorm output shape: {}x{}", normed.rows, normed.cols);
-        normed
     }
 }
 
-struct TransformerBlock {
-    attention: MultiHeadAttention,
-    feed_forward: FeedForward,
-    norm1: LayerNorm,
-    norm2: LayerNorm,
+// C += A * B^T for row-major A (m x k), B (n x k) and C (m x n).
+// Both operands are walked along contiguous rows, so every entry of C is a
+// dot product over a KC-long panel that stays in cache across the tile.
+fn gemm_nt<T: Float>(GemmDims { m, n, k, .. }: GemmDims, a: &[T], b: &[T], c: &mut [T]) {
+    for pc in (0..k).step_by(KC) {
+        let kb = KC.min(k - pc);
+        for ic in (0..m).step_by(MC) {
+            let i_end = (ic + MC).min(m);
+            for jc in (0..n).step_by(MC) {
+                let j_end = (jc + MC).min(n);
+                for i in ic..i_end {
+                    let a_row = &a[i * k + pc..i * k + pc + kb];
+                    for j in jc..j_end {
+                        c[i * n + j] += T::dot(a_row, &b[j * k + pc..j * k + pc + kb]);
+                    }
+                }
+            }
+        }
+    }
 }
 
-impl TransformerBlock {
-    fn new(heads: usize, dim: usize) -> Self {
-        println!("Creating TransformerBlock: heads={}, dim={}", heads, dim);
-        TransformerBlock {
-            attention: MultiHeadAttention::new(heads, dim),
-            feed_forward: FeedForward::new(dim, dim),
-            norm1: LayerNorm::new(dim),
-            norm2: LayerNorm::new(dim),
+// C += A^T * B for row-major A (k x m, row stride lda), B (k x n) and C (m x n).
+// Row p of B is shared by every row of C, so it is streamed through axpy4
+// against four entries of row p of A at a time.
+fn gemm_tn<T: Float>(GemmDims { m, n, k, lda }: GemmDims, a: &[T], b: &[T], c: &mut [T]) {
+    for jc in (0..n).step_by(NC) {
+        let nb = NC.min(n - jc);
+        for pc in (0..k).step_by(KC) {
+            let kb = KC.min(k - pc);
+            for ic in (0..m).step_by(MC) {
+                let i_end = (ic + MC).min(m);
+                let mut i = ic;
+                while i + 4 <= i_end {
+                    let (c0, rest) = c[i * n..(i + 4) * n].split_at_mut(n);
+                    let (c1, rest) = rest.split_at_mut(n);
+                    let (c2, c3) = rest.split_at_mut(n);
+                    let c0 = &mut c0[jc..jc + nb];
+                    let c1 = &mut c1[jc..jc + nb];
+                    let c2 = &mut c2[jc..jc + nb];
+                    let c3 = &mut c3[jc..jc + nb];
+                    for p in pc..pc + kb {
+                        let alphas = [a[p * lda + i], a[p * lda + i + 1], a[p * lda + i + 2], a[p * lda + i + 3]];
+                        let b_row = &b[p * n + jc..p * n + jc + nb];
+                        T::axpy4(alphas, b_row, [&mut *c0, &mut *c1, &mut *c2, &mut *c3]);
+                    }
+                    i += 4;
+                }
+                for i in i..i_end {
+                    let c_row = &mut c[i * n + jc..i * n + jc + nb];
+                    for p in pc..pc + kb {
+                        T::axpy(a[p * lda + i], &b[p * n + jc..p * n + jc + nb], c_row);
+                    }
+                }
+            }
         }
     }
+}
+
+
+
+
+
+
 
-    fn backward(&mut self, gradients: &Matrix, learning_rate: f64) -> Matrix {
-        println!("TransformerBlock backward pass");
-        // Backpropagate through feed forward layer
-        let ff_gradients = self.feed_forward.backward(gradients, learning_rate);
 
-        // Backpropagate through layer norm 2
-        let norm2_gradients = self.norm2.backward(&ff_gradients, learning_rate);
 
-        // Backpropagate through attention layer
-        let attention_gradients = self.attention.backward(&norm2_gradients, learning_rate);
 
-        // Backpropagate through layer norm 1
-        let norm1_gradients = self.norm1.backward(&attention_gradients, learning_rate);
 
-        norm1_gradients
+// Row-major strides for a contiguous tensor of the given shape
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for d in (0..shape.len().saturating_sub(1)).rev() {
+        strides[d] = strides[d + 1] * shape[d + 1];
     }
+    strides
+}
 
-    fn forward(&self, input: &Matrix) -> Matrix {
-        println!("TransformerBlock forward pass");
-        let attention_output = self.attention.forward(input, input, input);
-        let normed_attention_output = self.norm1.forward(&input.add(&attention_output));
-        let feed_forward_output = self.feed_forward.forward(&normed_attention_output);
-        let output = self.norm2.forward(&normed_attention_output.add(&feed_forward_output));
-        println!("TransformerBlock output shape: {}x{}", output.rows, output.cols);
-        output
+// Shape that two operands broadcast to under NumPy's rules: trailing axes are
+// aligned and each pair must match or contain a 1
+fn broadcast_shapes(a: &[usize], b: &[usize]) -> Option<Vec<usize>> {
+    let ndim = a.len().max(b.len());
+    let mut shape = Vec::with_capacity(ndim);
+    for d in 0..ndim {
+        let dim_a = if d < ndim - a.len() { 1 } else { a[d - (ndim - a.len())] };
+        let dim_b = if d < ndim - b.len() { 1 } else { b[d - (ndim - b.len())] };
+        shape.push(match (dim_a, dim_b) {
+            (x, y) if x == y => x,
+            (1, y) => y,
+            (x, 1) => x,
+            _ => return None,
+        });
     }
+    Some(shape)
 }
 
-struct Transformer {
-    embedding: Embedding,
-    blocks: Vec<TransformerBlock>,
-    output_layer: FeedForward,
+// Visit every index of `shape` in row-major order, passing f the flat offset
+// of that index into each operand's (strides, offset) layout
+fn for_each_offset<F: FnMut(&[usize])>(shape: &[usize], layouts: &[(&[usize], usize)], mut f: F) {
+    let total: usize = shape.iter().product();
+    let mut index = vec![0; shape.len()];
+    let mut offsets: Vec<usize> = layouts.iter().map(|&(_, offset)| offset).collect();
+    for _ in 0..total {
+        f(&offsets);
+        for d in (0..shape.len()).rev() {
+            index[d] += 1;
+            if index[d] < shape[d] {
+                for (offset, (strides, _)) in offsets.iter_mut().zip(layouts) {
+                    *offset += strides[d];
+                }
+                break;
+            }
+            for (offset, (strides, _)) in offsets.iter_mut().zip(layouts) {
+                *offset -= strides[d] * (shape[d] - 1);
+            }
+            index[d] = 0;
+        }
+    }
 }
 
-impl Transformer {
-    fn new(vocab_size: usize, embedding_dim: usize, num_blocks: usize, heads: usize) -> Self {
-        println!("Creating Transformer: vocab_size={}, embedding_dim={}, num_blocks={}, heads={}", vocab_size, embedding_dim, num_blocks, heads);
-        let mut rng = Rng::new(12242);  // Use a fixed seed for reproducibility
-        let mut embedding = Embedding::new(vocab_size, embedding_dim);
-        initialize_weights(&mut embedding.embeddings, &mut rng);
+// Owned, contiguous N-dimensional tensor
+#[derive(Clone)]
+struct Tensor<T> {
+    data: Vec<T>,
+    shape: Vec<usize>,
+}
 
-        let mut blocks = Vec::new();
-        for i in 0..num_blocks {
-            println!("Initializing TransformerBlock {}", i);
-            let mut block = TransformerBlock::new(heads, embedding_dim);
-            initialize_weights(&mut block.attention.w_q, &mut rng);
-            initialize_weights(&mut block.attention.w_k, &mut rng);
-            initialize_weights(&mut block.attention.w_v, &mut rng);
-            initialize_weights(&mut block.attention.w_o, &mut rng);
-            initialize_weights(&mut block.feed_forward.w1, &mut rng);
-            initialize_weights(&mut block.feed_forward.w2, &mut rng);
-            blocks.push(block);
-        }
+// Borrowed, strided window onto tensor or matrix storage. Reshape, permute,
+// slice and broadcast only rewrite the shape and strides.
+#[derive(Clone)]
+struct TensorView<'a, T> {
+    data: &'a [T],
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+    offset: usize,
+}
 
-        let mut output_layer = FeedForward::new(embedding_dim, vocab_size);
-        initialize_weights(&mut output_layer.w1, &mut rng);
-        initialize_weights(&mut output_layer.w2, &mut rng);
+impl<T: Float> Tensor<T> {
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn zeros(shape: &[usize]) -> Self {
+        println!("Creating new Tensor: {:?}", shape);
+        Tensor { data: vec![T::zero(); shape.iter().product()], shape: shape.to_vec() }
+    }
 
-        Transformer {
-            embedding,
-            blocks,
-            output_layer,
+    fn from_vec(data: Vec<T>, shape: &[usize]) -> Result<Self, TensorError> {
+        if data.len() != shape.iter().product::<usize>() {
+            return Err(TensorError::ShapeMismatch { op: "tensor from data", left: vec![data.len()], right: shape.to_vec() });
         }
+        Ok(Tensor { data, shape: shape.to_vec() })
     }
 
-    fn forward(&self, input: &[usize]) -> Matrix {
-        println!("Transformer forward pass");
-        let mut x = self.embedding.forward(input.to_vec());
-        println!("Embedded input shape: {}x{}", x.rows, x.cols);
-        x = x.add(&positional_encoding(x.rows, x.cols));
-        println!("After positional encoding: {}x{}", x.rows, x.cols);
+    fn from_matrix(matrix: Matrix<T>) -> Self {
+        Tensor { data: matrix.data, shape: vec![matrix.rows, matrix.cols] }
+    }
 
-        for (i, block) in self.blocks.iter().enumerate() {
-            println!("Processing TransformerBlock {}", i);
-            x = block.forward(&x);
-            println!("After block {}: {}x{}", i, x.rows, x.cols);
-        }
+    // Flatten the leading axes into rows and keep the last axis as columns
+    fn into_matrix(self) -> Matrix<T> {
+        let cols = self.shape.last().copied().unwrap_or(1);
+        let rows = self.shape.iter().rev().skip(1).product();
+        Matrix { rows, cols, data: self.data }
+    }
 
-        println!("Applying output layer");
-        let output = self.output_layer.forward(&x);
-        println!("Final output shape: {}x{}", output.rows, output.cols);
-        output
+    fn view(&self) -> TensorView<'_, T> {
+        TensorView { data: &self.data, shape: self.shape.clone(), strides: contiguous_strides(&self.shape), offset: 0 }
     }
 
-    fn train(&mut self, input: &[usize], target: &[usize], learning_rate: f64, tokenizer: &mut Tokenizer, temperature: f64) -> f64 {
-        println!("Training on input of length {}", input.len());
-        let output = self.forward(input);
-        let mut loss = 0.0;
+    fn reshape(self, shape: &[usize]) -> Result<Tensor<T>, TensorError> {
+        Tensor::from_vec(self.data, shape)
+    }
 
-        let mut gradients = Matrix::new(output.rows, output.cols);
-        for i in 0..output.rows {
-            let target_index = if i < target.len() { target[i] } else { 0 };
-            let row: Vec<f64> = (0..output.cols).map(|j| output.get(i, j)).collect();
-            let probs = softmax(&row);
-            for j in 0..output.cols {
-                gradients.set(i, j, probs[j] - if j == target_index { 1.0 } else { 0.0 });
-                if j == target_index {
-                    loss -= (probs[j] + 1e-10).ln();
-                }
-            }
+    fn scale_inplace(&mut self, scalar: T) {
+        for value in &mut self.data {
+            *value *= scalar;
         }
+    }
 
-        println!("Calculated loss: {}", loss);
-
-        // Generate and print prediction using generate_sequence
-        let input_words: Vec<String> = input.iter().map(|&t| tokenizer.decode(t).to_string()).collect();
-        let input_text = input_words.join(" ");
+    // Softmax over the last axis, in place, with rows split across the pool
+    fn softmax_last_axis(&mut self) {
+        let last = self.shape.last().copied().unwrap_or(1);
+        ThreadPool::global().for_each_row_block(&mut self.data, last, |_, block| {
+            for row in block.chunks_exact_mut(last) {
+                let probs = softmax(row);
+                row.copy_from_slice(&probs);
+            }
+        });
+    }
+}
 
-        let generated_sequence = self.generate_sequence(&input_text, tokenizer, temperature);
+impl<'a, T: Float> TensorView<'a, T> {
+    fn numel(&self) -> usize {
+        self.shape.iter().product()
+    }
 
-        println!("Input: '{}...{}'", 
-            input_text.chars().take(20).collect::<String>(),
-            input_text.chars().rev().take(40).collect::<String>().chars().rev().collect::<String>()
-        );
-        println!("Predicted next tokens (multiple words): '{}'", generated_sequence);
-        println!("Actual next token: '{}'", tokenizer.decode(target[target.len() - 1]));
-        println!("Calculated loss: {}", loss);
+    fn is_contiguous(&self) -> bool {
+        self.shape.iter().zip(&self.strides).zip(contiguous_strides(&self.shape)).all(|((&dim, &stride), expected)| dim <= 1 || stride == expected)
+    }
 
-        // Backpropagate through output layer
-        println!("Backpropagating through output layer");
-        let output_gradients = self.output_layer.backward(&gradients, learning_rate);
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn get(&self, index: &[usize]) -> Result<T, TensorError> {
+        if index.len() != self.shape.len() || index.iter().zip(&self.shape).any(|(i, d)| i >= d) {
+            return Err(TensorError::OutOfBounds { index: index.to_vec(), shape: self.shape.clone() });
+        }
+        Ok(self.data[self.offset + index.iter().zip(&self.strides).map(|(i, s)| i * s).sum::<usize>()])
+    }
 
-        // Backpropagate through transformer blocks
-        println!("Backpropagating through transformer blocks");
-        let mut block_gradients = output_gradients;
-        for (i, block) in self.blocks.iter_mut().enumerate().rev() {
-            println!("Backpropagating through block {}", i);
-            block_gradients = block.backward(&block_gradients, learning_rate);
+    // Reinterpret a contiguous view with a new shape
+    fn reshape(&self, shape: &[usize]) -> Result<TensorView<'a, T>, TensorError> {
+        if !self.is_contiguous() {
+            return Err(TensorError::NonContiguous { op: "reshape", shape: self.shape.clone(), strides: self.strides.clone() });
+        }
+        if shape.iter().product::<usize>() != self.numel() {
+            return Err(TensorError::ShapeMismatch { op: "reshape", left: self.shape.clone(), right: shape.to_vec() });
         }
+        Ok(TensorView { data: self.data, shape: shape.to_vec(), strides: contiguous_strides(shape), offset: self.offset })
+    }
 
-        // Update embedding layer
-        println!("Updating embedding layer");
-        for i in 0..input.len() {
-            if i >= self.embedding.embeddings.rows {
-                println!("Warning: input token {} is out of embedding range", input[i]);
-                continue;
-            }
-            for j in 0..self.embedding.embedding_dim {
-                if j >= block_gradients.cols {
-                    println!("Warning: j={} is out of range for block_gradients", j);
-                    continue;
-                }
-                self.embedding.embeddings.set(input[i], j, 
-                    self.embedding.embeddings.get(input[i], j) - learning_rate * block_gradients.get(i, j));
+    // Reorder the axes; axes[i] names the source axis that becomes axis i
+    fn permute(&self, axes: &[usize]) -> Result<TensorView<'a, T>, TensorError> {
+        let mut seen = vec![false; self.shape.len()];
+        for &axis in axes {
+            if axis >= seen.len() || seen[axis] {
+                return Err(TensorError::InvalidAxes { op: "permute", axes: axes.to_vec(), shape: self.shape.clone() });
             }
+            seen[axis] = true;
         }
-        // Generate and print prediction
-        let input_words: Vec<String> = input.iter().map(|&t| tokenizer.decode(t).to_string()).collect();
-        let input_text = input_words.join(" ");
-        let prediction = self.predict_next_token(input, tokenizer, temperature);
-        println!("Input: '{}...'", input_text.chars().take(20).collect::<String>());
-        println!("Actual next token: '{}'", tokenizer.decode(target[target.len() - 1]));
-        println!("Batch loss: {}", loss);
+        if axes.len() != self.shape.len() {
+            return Err(TensorError::InvalidAxes { op: "permute", axes: axes.to_vec(), shape: self.shape.clone() });
+        }
+        Ok(TensorView {
+            data: self.data,
+            shape: axes.iter().map(|&a| self.shape[a]).collect(),
+            strides: axes.iter().map(|&a| self.strides[a]).collect(),
+            offset: self.offset,
+        })
+    }
 
-        loss
+    // Restrict one axis to start..end
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn slice(&self, axis: usize, start: usize, end: usize) -> Result<TensorView<'a, T>, TensorError> {
+        if axis >= self.shape.len() || start > end || end > self.shape[axis] {
+            return Err(TensorError::InvalidSlice { axis, start, end, shape: self.shape.clone() });
+        }
+        let mut shape = self.shape.clone();
+        shape[axis] = end - start;
+        Ok(TensorView { data: self.data, shape, strides: self.strides.clone(), offset: self.offset + start * self.strides[axis] })
     }
 
-    fn predict_next_token(&self, input: &[usize], tokenizer: &Tokenizer, temperature: f64) -> usize {
-        let output = self.forward(input);
-        let last_row: Vec<f64> = (0..output.cols).map(|j| output.get(output.rows - 1, j)).collect();
-        let mut logits = last_row;
-        
-        // Apply temperature
-        logits.iter_mut().for_each(|logit| *logit /= temperature);
-        
-        let mut probs = softmax(&logits);
-        
-        // Set probability of UNK token to 0
-        let unk_index = tokenizer.vocab.iter().position(|(word, _)| word == "<UNK>").unwrap_or(0);
-        probs[unk_index] = 0.0;
-        
-        // Renormalize probabilities
-        let sum: f64 = probs.iter().sum();
-        probs.iter_mut().for_each(|p| *p /= sum);
-        
-        // Sample from the distribution using a simple random number generator
-        let mut rng = Rng::new(14342); 
-        let random_value = rng.next_f64();
-        let mut cumulative_prob = 0.0;
-        for (index, &prob) in probs.iter().enumerate() {
-            cumulative_prob += prob;
-            if random_value < cumulative_prob {
-                return index;
+    // Expand size-1 and missing leading axes to `shape` using zero strides
+    fn broadcast_to(&self, shape: &[usize]) -> Result<TensorView<'a, T>, TensorError> {
+        let mismatch = || TensorError::ShapeMismatch { op: "broadcast", left: self.shape.clone(), right: shape.to_vec() };
+        let lead = shape.len().checked_sub(self.shape.len()).ok_or_else(mismatch)?;
+        let mut strides = vec![0; shape.len()];
+        for (d, &dim) in self.shape.iter().enumerate() {
+            if dim != shape[lead + d] && dim != 1 {
+                return Err(mismatch());
             }
+            strides[lead + d] = if dim == 1 { 0 } else { self.strides[d] };
         }
-        probs.len() - 1 // Fallback to the last token if sampling fails
+        Ok(TensorView { data: self.data, shape: shape.to_vec(), strides, offset: self.offset })
     }
 
-    fn generate_sequence(&self, prompt: &str, tokenizer: &mut Tokenizer, temperature: f64) -> String {
-        let mut input_tokens = tokenizer.tokenize(prompt);
-        let mut generated_words = Vec::with_capacity(10);
-        println!("Generating sequence from prompt: '{}'", prompt);
+    // Write the view into out in row-major order, a whole run at a time when
+    // the last axis is contiguous
+    fn copy_into(&self, out: &mut [T]) -> Result<(), TensorError> {
+        if out.len() != self.numel() {
+            return Err(TensorError::ShapeMismatch { op: "tensor copy", left: self.shape.clone(), right: vec![out.len()] });
+        }
+        if self.is_contiguous() {
+            out.copy_from_slice(&self.data[self.offset..self.offset + out.len()]);
+            return Ok(());
+        }
+        let ndim = self.shape.len();
+        let mut written = 0;
+        if self.strides[ndim - 1] == 1 {
+            let run = self.shape[ndim - 1];
+            for_each_offset(&self.shape[..ndim - 1], &[(&self.strides[..ndim - 1], self.offset)], |offsets| {
+                out[written..written + run].copy_from_slice(&self.data[offsets[0]..offsets[0] + run]);
+                written += run;
+            });
+        } else {
+            for_each_offset(&self.shape, &[(&self.strides, self.offset)], |offsets| {
+                out[written] = self.data[offsets[0]];
+                written += 1;
+            });
+        }
+        Ok(())
+    }
 
-        let mut rng = Rng::new(24342);
-        let unk_index = tokenizer.vocab.iter().position(|(word, _)| word == "<UNK>").unwrap_or(0);
+    // Copy the view into a new contiguous tensor
+    fn to_tensor(&self) -> Tensor<T> {
+        let mut data = vec![T::zero(); self.numel()];
+        self.copy_into(&mut data).expect("buffer sized from the view");
+        Tensor { data, shape: self.shape.clone() }
+    }
 
-        for i in 0..10 {
-            let output = self.forward(&input_tokens);
-            let last_row: Vec<f64> = (0..output.cols).map(|j| output.get(output.rows - 1, j) / temperature).collect();
-            
-            let mut probs = softmax(&last_row);
-            probs[unk_index] = 0.0;
-            
-            let sum: f64 = probs.iter().sum();
-            probs.iter_mut().for_each(|p| *p /= sum);
-            
-            let random_value = rng.next_f64();
-            let mut cumulative_prob = 0.0;
-            let next_token = probs.iter().enumerate()
-                .find(|(_, &prob)| {
-                    cumulative_prob += prob;
-                    random_value < cumulative_prob
-                })
-                .map(|(index, _)| index)
-                .unwrap_or(probs.len() - 1);
-
-            let next_word = tokenizer.decode(next_token).to_string();
-            println!("Generated token {}: '{}'", i + 1, next_word);
-            generated_words.push(next_word);
+    // Elementwise f(a, b) with broadcasting
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn zip_with<F: Fn(T, T) -> T>(&self, other: &TensorView<T>, f: F) -> Result<Tensor<T>, TensorError> {
+        let shape = broadcast_shapes(&self.shape, &other.shape)
+            .ok_or_else(|| TensorError::ShapeMismatch { op: "elementwise op", left: self.shape.clone(), right: other.shape.clone() })?;
+        let (a, b) = (self.broadcast_to(&shape)?, other.broadcast_to(&shape)?);
+        let mut data = Vec::with_capacity(shape.iter().product());
+        for_each_offset(&shape, &[(&a.strides, a.offset), (&b.strides, b.offset)], |offsets| {
+            data.push(f(a.data[offsets[0]], b.data[offsets[1]]));
+        });
+        Tensor::from_vec(data, &shape)
+    }
 
-            input_tokens.push(next_token);
-            if input_tokens.len() > self.embedding.embeddings.rows {
-                input_tokens.remove(0);
-            }
-        }
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn add(&self, other: &TensorView<T>) -> Result<Tensor<T>, TensorError> {
+        self.zip_with(other, |a, b| a + b)
+    }
 
-        let generated_sequence = generated_words.join(" ");
-        println!("Complete generated sequence: '{}'", generated_sequence);
-        
-        // Print all tokens at once
-        println!("All generated tokens: {:?}", generated_words);
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn sub(&self, other: &TensorView<T>) -> Result<Tensor<T>, TensorError> {
+        self.zip_with(other, |a, b| a - b)
+    }
 
-        generated_sequence
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn mul(&self, other: &TensorView<T>) -> Result<Tensor<T>, TensorError> {
+        self.zip_with(other, |a, b| a * b)
     }
-}
 
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn div(&self, other: &TensorView<T>) -> Result<Tensor<T>, TensorError> {
+        self.zip_with(other, |a, b| a / b)
+    }
 
-fn main() {
-    println!("Starting main function");
-    // Read the text file
-    let contents = include_str!("../Heany.txt");
-    println!("Read file contents, length: {}", contents.len());
+    // Whether the last two axes are stored row-major (false) or transposed
+    // (true) as one dense block the gemm kernels can read in place
+    fn matrix_layout(&self) -> Option<bool> {
+        let ndim = self.shape.len();
+        let (rows, cols) = (self.shape[ndim - 2], self.shape[ndim - 1]);
+        let (row_stride, col_stride) = (self.strides[ndim - 2], self.strides[ndim - 1]);
+        if (rows <= 1 || row_stride == cols) && (cols <= 1 || col_stride == 1) {
+            Some(false)
+        } else if (rows <= 1 || row_stride == 1) && (cols <= 1 || col_stride == rows) {
+            Some(true)
+        } else {
+            None
+        }
+    }
 
-    // Tokenize the text
-    let mut tokenizer = Tokenizer::new();
-    let tokens = tokenizer.tokenize(&contents);
-    println!("Tokenized text, number of tokens: {}", tokens.len());
+    // Offset of each matrix block, one per index of the broadcast leading axes
+    fn batch_offsets(&self, batch_shape: &[usize]) -> Result<Vec<usize>, TensorError> {
+        let lead = self.shape.len() - 2;
+        let batch = TensorView { data: self.data, shape: self.shape[..lead].to_vec(), strides: self.strides[..lead].to_vec(), offset: self.offset }
+            .broadcast_to(batch_shape)?;
+        let mut offsets = Vec::with_capacity(batch_shape.iter().product());
+        for_each_offset(batch_shape, &[(&batch.strides, batch.offset)], |offset| offsets.push(offset[0]));
+        Ok(offsets)
+    }
 
-    // Define training parameters
-    let seq_length = 40;
-    let epochs = 4;
-    let learning_rate = 0.01;
+    // Shape of self * other for [..., m, k] by [..., k, n]: the broadcast
+    // leading axes, then m, k and n
+    fn matmul_dims(&self, other: &TensorView<T>) -> Result<(Vec<usize>, usize, usize, usize), TensorError> {
+        let mismatch = || TensorError::ShapeMismatch { op: "matmul", left: self.shape.clone(), right: other.shape.clone() };
+        let (a_lead, b_lead) = (self.shape.len().checked_sub(2).ok_or_else(mismatch)?, other.shape.len().checked_sub(2).ok_or_else(mismatch)?);
+        let (m, k) = (self.shape[a_lead], self.shape[a_lead + 1]);
+        let (k2, n) = (other.shape[b_lead], other.shape[b_lead + 1]);
+        let batch_shape = broadcast_shapes(&self.shape[..a_lead], &other.shape[..b_lead]).filter(|_| k == k2).ok_or_else(mismatch)?;
+        Ok((batch_shape, m, k, n))
+    }
 
-    let total_iterations = epochs * (tokens.len() - seq_length - 1);
-    let mut current_iteration = 0;
+    // out += self * other, with out holding the row-major [..., m, n] result.
+    // A transposed block, as left by permuting the last two axes, is read in
+    // place by the NT or TN kernel; other layouts, and the transposed-by-
+    // transposed case no kernel covers, are copied row-major first.
+    fn matmul_into(&self, other: &TensorView<T>, out: &mut [T]) -> Result<(), TensorError> {
+        let (batch_shape, m, k, n) = self.matmul_dims(other)?;
+        let batches: usize = batch_shape.iter().product();
+        if out.len() != batches * m * n {
+            return Err(TensorError::ShapeMismatch { op: "matmul output", left: batch_shape.iter().copied().chain([m, n]).collect(), right: vec![out.len()] });
+        }
+        println!("Performing tensor matmul: {:?} * {:?}", self.shape, other.shape);
+
+        let a_copy = self.matrix_layout().is_none().then(|| self.to_tensor());
+        let a = a_copy.as_ref().map_or_else(|| self.clone(), Tensor::view);
+        let a_transposed = a.matrix_layout() == Some(true);
+        let b_copy = match other.matrix_layout() {
+            None => Some(other.to_tensor()),
+            Some(true) if a_transposed => Some(other.to_tensor()),
+            Some(_) => None,
+        };
+        let b = b_copy.as_ref().map_or_else(|| other.clone(), Tensor::view);
+        let op = match (a_transposed, b.matrix_layout() == Some(true)) {
+            (false, false) => GemmOp::NN,
+            (false, true) => GemmOp::NT,
+            _ => GemmOp::TN,
+        };
+        if m * n == 0 {
+            return Ok(());
+        }
+        let (a_offsets, b_offsets) = (a.batch_offsets(&batch_shape)?, b.batch_offsets(&batch_shape)?);
+        let a_block = |batch: usize| &a.data[a_offsets[batch]..a_offsets[batch] + m * k];
+        let b_block = |batch: usize| &b.data[b_offsets[batch]..b_offsets[batch] + k * n];
 
-    // Initialize the transformer
-    let vocab_size = tokenizer.vocab_size();
-    let embedding_dim = 128;
-    let num_blocks = 3;
-    let heads = 4;
+        if batches == 1 {
+            parallel_gemm(op, m, n, k, a_block(0), b_block(0), out);
+        } else {
+            // Many small products: give each worker whole batch entries
+            let (kernel, lda) = op.kernel::<T::Acc>(m, k);
+            ThreadPool::global().for_each_row_block(out, m * n, |first, block| {
+                for (i, c) in block.chunks_exact_mut(m * n).enumerate() {
+                    T::gemm(kernel, GemmDims { m, n, k, lda }, a_block(first + i), b_block(first + i), c);
+                }
+            });
+        }
+        Ok(())
+    }
 
-    println!("Initializing transformer with vocab_size={}, embedding_dim={}, num_blocks={}, heads={}", 
-             vocab_size, embedding_dim, num_blocks, heads);
-    let mut transformer = Transformer::new(vocab_size, embedding_dim, num_blocks, heads);
+    // Batched matmul of [..., m, k] by [..., k, n] into a new tensor
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn matmul(&self, other: &TensorView<T>) -> Result<Tensor<T>, TensorError> {
+        let (batch_shape, m, _, n) = self.matmul_dims(other)?;
+        let mut result = Tensor::zeros(&batch_shape.into_iter().chain([m, n]).collect::<Vec<usize>>());
+        self.matmul_into(other, &mut result.data)?;
+        Ok(result)
+    }
+}
 
-    let temperature = 0.8;
+fn softmax<T: Float>(input: &[T]) -> Vec<T> {
+    //println!("Applying softmax to vector of length {}", input.len());
+    let max_val = T::reduce_max(input);
+    let exp_vals: Vec<T> = input.iter().map(|&x| (x - max_val).exp()).collect();
+    let sum_exp_vals = T::reduce_sum(&exp_vals);
+    let mut probs = vec![T::zero(); exp_vals.len()];
+    T::scale_into(T::one() / sum_exp_vals, &exp_vals, &mut probs);
+    probs
+}
 
-    println!("Starting training loop: seq_length={}, epochs={}, learning_rate={}", seq_length, epochs, learning_rate);
-    let batch_size = 32;
-    for epoch in 0..epochs {
-        println!("Starting epoch {}", epoch + 1);
-        let mut total_loss = 0.0;
-        let mut batch_count = 0;
-        for i in (0..tokens.len() - seq_length - 1).step_by(batch_size) {
-            let mut batch_loss = 0.0;
-            for j in 0..batch_size {
-                if i + j >= tokens.len() - seq_length - 1 {
-                    break;
-                }
-                let input = &tokens[i+j..i+j+seq_length];
-                let target = &tokens[i+j+1..i+j+seq_length+1];
-                
-                batch_loss += transformer.train(input, target, learning_rate, &mut tokenizer, temperature);
+// Normalize each row of input to zero mean and unit variance into normed,
+// returning 1 / std for each row
+fn normalize_rows<T: Float>(input: &Matrix<T>, normed: &mut Matrix<T>) -> Vec<T> {
+    let eps = T::from_f64(1e-6);
+    let dim = T::from_f64(input.cols as f64);
+    let mut inv_std = Vec::with_capacity(input.rows);
+    for i in 0..input.rows {
+        let row = input.row(i);
+        let mean = T::reduce_sum(row) / dim;
+        let std_dev = (T::sum_sq_dev(row, mean) / dim + eps).sqrt();
+        for (n, &x) in normed.row_mut(i).iter_mut().zip(row) {
+            *n = (x - mean) / std_dev;
+        }
+        inv_std.push(T::one() / std_dev);
+    }
+    inv_std
+}
 
-            }
-            total_loss += batch_loss;
-            batch_count += 1;
-            
-            current_iteration += batch_size;
-            let progress = (current_iteration as f64 / total_iterations as f64) * 100.0;
-            println!("Progress: {:.2}% ({}/{})", progress, current_iteration, total_iterations);
+// Accumulate gradients through y = gamma * normed + beta, where normed and
+// inv_std come from normalize_rows. With dn = dy * gamma, the input gradient
+// is inv_std * (dn - mean(dn) - normed * mean(dn * normed)).
+fn layer_norm_backward<T: Float>(
+    dy: &Matrix<T>,
+    normed: &Matrix<T>,
+    inv_std: &[T],
+    gamma: &Matrix<T>,
+    d_input: &mut Matrix<T>,
+    d_gamma: &mut Matrix<T>,
+    d_beta: &mut Matrix<T>,
+) {
+    let dim = T::from_f64(dy.cols as f64);
+    dy.sum_rows_into(d_beta);
+    let mut d_normed = vec![T::zero(); dy.cols];
+    for (i, &inv_std) in inv_std.iter().enumerate() {
+        for (((d_g, dn), &dy), (&n, &g)) in d_gamma.row_mut(0).iter_mut().zip(&mut d_normed).zip(dy.row(i)).zip(normed.row(i).iter().zip(gamma.row(0))) {
+            *d_g += dy * n;
+            *dn = dy * g;
+        }
+        let mean_d = T::reduce_sum(&d_normed) / dim;
+        let mean_dn = T::dot(&d_normed, normed.row(i)) / dim;
+        for ((d, &dn), &n) in d_input.row_mut(i).iter_mut().zip(&d_normed).zip(normed.row(i)) {
+            *d += inv_std * (dn - mean_d - n * mean_dn);
+        }
+    }
+}
 
-            println!("Epoch {}, Batch {}: Average Loss = {}", epoch + 1, batch_count, batch_loss / batch_size as f64);
+// Accumulate the gradient through a row-wise softmax with output y:
+// dx += y * (dy - sum(dy * y)) for each row
+fn softmax_backward<T: Float>(y: &[T], dy: &[T], dx: &mut [T], row_len: usize) {
+    for ((y, dy), dx) in y.chunks_exact(row_len).zip(dy.chunks_exact(row_len)).zip(dx.chunks_exact_mut(row_len)) {
+        let dot = T::dot(dy, y);
+        for (d, (&y, &dy)) in dx.iter_mut().zip(y.iter().zip(dy)) {
+            *d += y * (dy - dot);
         }
-        println!("Epoch {} completed, Average Loss: {}", epoch + 1, total_loss / batch_count as f64);
     }
+}
 
 
-    // Generate predictions
-    let prompt = "Between my finger and my thumb";
-    println!("Generating predictions for prompt: '{}'", prompt);
 
-    let temperature = 0.8;
-    let generated_sequence = transformer.generate_sequence(prompt, &mut tokenizer, temperature);
-    
-    println!("Generated sequence: {}", generated_sequence);
-    println!("Prediction generation completed");
+
+
+
+
+
+
+
+// Handle to a value recorded on a Tape
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Var(usize);
+
+// How a tape value was computed, plus whatever its backward step needs
+#[cfg(test)]
+enum Op<T> {
+    Leaf,
+    MatMul(Var, Var),
+    MatMulTransposed(Var, Var),
+    Add(Var, Var),
+    AddRow(Var, Var),
+    Scale(Var, T),
+    Relu(Var),
+    Softmax(Var),
+    LayerNorm { input: Var, gamma: Var, beta: Var, normed: Matrix<T>, inv_std: Vec<T> },
+    Gather(Var, Vec<usize>),
+    Columns(Var, usize),
+    ConcatColumns(Vec<Var>),
+}
+
+#[cfg(test)]
+struct Node<T> {
+    value: Matrix<T>,
+    op: Op<T>,
+}
+
+// Reverse-mode autograd: every op records its inputs as it runs, and backward
+// replays the record in reverse to accumulate a gradient for each value.
+// Values and gradients are borrowed from the workspace and handed back by
+// release. Training runs on the layers' hand-written backward passes; the
+// tape is built for tests only, as the independent reference those passes
+// are gradient-checked against.
+#[cfg(test)]
+struct Tape<'a, T> {
+    ws: &'a Workspace<T>,
+    nodes: Vec<Node<T>>,
+    params: Vec<Var>,
+}
+
+// Gradients from Tape::backward, indexed by Var
+#[cfg(test)]
+struct Gradients<T> {
+    grads: Vec<Option<Matrix<T>>>,
+}
+
+#[cfg(test)]
+impl<T: Float> Gradients<T> {
+    fn take(&mut self, var: Var) -> Option<Matrix<T>> {
+        self.grads.get_mut(var.0).and_then(|grad| grad.take())
+    }
+
+    fn release(self, ws: &Workspace<T>) {
+        for grad in self.grads.into_iter().flatten() {
+            ws.give(grad);
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a, T: Float> Tape<'a, T> {
+    fn new(ws: &'a Workspace<T>) -> Self {
+        Tape { ws, nodes: Vec::new(), params: Vec::new() }
+    }
+
+    fn push(&mut self, value: Matrix<T>, op: Op<T>) -> Var {
+        self.nodes.push(Node { value, op });
+        Var(self.nodes.len() - 1)
+    }
+
+    fn value(&self, var: Var) -> &Matrix<T> {
+        &self.nodes[var.0].value
+    }
+
+    // Record an input value, ideally taken from the workspace since release
+    // hands it back there; gradients flow to it but not through it
+    fn leaf(&mut self, value: Matrix<T>) -> Var {
+        self.push(value, Op::Leaf)
+    }
+
+    // Copy a parameter onto the tape and register it, so its gradient can be
+    // matched back to the layer in registration order
+    fn param(&mut self, matrix: &Matrix<T>) -> Var {
+        let mut value = self.ws.take(matrix.rows, matrix.cols);
+        value.data.copy_from_slice(&matrix.data);
+        let var = self.leaf(value);
+        self.params.push(var);
+        var
+    }
+
+    fn matmul(&mut self, a: Var, b: Var) -> Result<Var, TensorError> {
+        let value = self.ws.gemm(GemmOp::NN, self.value(a), self.value(b))?;
+        Ok(self.push(value, Op::MatMul(a, b)))
+    }
+
+    // a * b^T
+    fn matmul_transposed(&mut self, a: Var, b: Var) -> Result<Var, TensorError> {
+        let value = self.ws.gemm(GemmOp::NT, self.value(a), self.value(b))?;
+        Ok(self.push(value, Op::MatMulTransposed(a, b)))
+    }
+
+    fn add(&mut self, a: Var, b: Var) -> Result<Var, TensorError> {
+        let (x, y) = (self.value(a), self.value(b));
+        let mut value = self.ws.take(x.rows, x.cols);
+        value.data.copy_from_slice(&x.data);
+        value.try_add_assign(y)?;
+        Ok(self.push(value, Op::Add(a, b)))
+    }
+
+    // Add a 1 x n bias row to every row of a
+    fn add_row(&mut self, a: Var, bias: Var) -> Result<Var, TensorError> {
+        let (x, b) = (self.value(a), self.value(bias));
+        if b.rows != 1 || b.cols != x.cols {
+            return Err(TensorError::ShapeMismatch { op: "bias addition", left: x.shape(), right: b.shape() });
+        }
+        let mut value = self.ws.take(x.rows, x.cols);
+        value.data.copy_from_slice(&x.data);
+        for i in 0..value.rows {
+            T::axpy(T::one(), b.row(0), value.row_mut(i));
+        }
+        Ok(self.push(value, Op::AddRow(a, bias)))
+    }
+
+    fn scale(&mut self, a: Var, scalar: T) -> Var {
+        let x = self.value(a);
+        let mut value = self.ws.take(x.rows, x.cols);
+        value.data.copy_from_slice(&x.data);
+        value.scale_inplace(scalar);
+        self.push(value, Op::Scale(a, scalar))
+    }
+
+    fn relu(&mut self, a: Var) -> Var {
+        let x = self.value(a);
+        let mut value = self.ws.take(x.rows, x.cols);
+        for (out, &input) in value.data.iter_mut().zip(&x.data) {
+            *out = input.max(T::zero());
+        }
+        self.push(value, Op::Relu(a))
+    }
+
+    // Softmax over each row
+    fn softmax(&mut self, a: Var) -> Var {
+        let x = self.value(a);
+        let mut value = self.ws.take(x.rows, x.cols);
+        for i in 0..x.rows {
+            value.row_mut(i).copy_from_slice(&softmax(x.row(i)));
+        }
+        self.push(value, Op::Softmax(a))
+    }
+
+    // Normalize each row, then scale by gamma and shift by beta (both 1 x n)
+    fn layer_norm(&mut self, a: Var, gamma: Var, beta: Var) -> Result<Var, TensorError> {
+        let (x, g, b) = (self.value(a), self.value(gamma), self.value(beta));
+        if g.shape() != vec![1, x.cols] || b.shape() != vec![1, x.cols] {
+            return Err(TensorError::ShapeMismatch { op: "layer norm", left: x.shape(), right: g.shape() });
+        }
+        let mut normed = self.ws.take(x.rows, x.cols);
+        let inv_std = normalize_rows(x, &mut normed);
+        let mut value = self.ws.take(x.rows, x.cols);
+        for i in 0..x.rows {
+            for ((y, &n), (&g, &b)) in value.row_mut(i).iter_mut().zip(normed.row(i)).zip(g.row(0).iter().zip(b.row(0))) {
+                *y = g * n + b;
+            }
+        }
+        Ok(self.push(value, Op::LayerNorm { input: a, gamma, beta, normed, inv_std }))
+    }
+
+    // Rows of table picked out by ids
+    fn gather(&mut self, table: Var, ids: &[usize]) -> Result<Var, TensorError> {
+        let t = self.value(table);
+        let mut value = self.ws.take(ids.len(), t.cols);
+        for (i, &id) in ids.iter().enumerate() {
+            if id >= t.rows {
+                return Err(TensorError::OutOfBounds { index: vec![id], shape: vec![t.rows] });
+            }
+            value.row_mut(i).copy_from_slice(t.row(id));
+        }
+        Ok(self.push(value, Op::Gather(table, ids.to_vec())))
+    }
+
+    fn columns(&mut self, a: Var, start: usize, count: usize) -> Result<Var, TensorError> {
+        let x = self.value(a);
+        if start + count > x.cols {
+            return Err(TensorError::OutOfBounds { index: vec![0, start + count - 1], shape: x.shape() });
+        }
+        let mut value = self.ws.take(x.rows, count);
+        value.copy_columns(x, start);
+        Ok(self.push(value, Op::Columns(a, start)))
+    }
+
+    // Place the parts side by side; all must have the same number of rows
+    fn concat_columns(&mut self, parts: &[Var]) -> Result<Var, TensorError> {
+        let rows = self.value(parts[0]).rows;
+        let cols = parts.iter().map(|&part| self.value(part).cols).sum();
+        let mut value = self.ws.take(rows, cols);
+        let mut start = 0;
+        for &part in parts {
+            let x = self.value(part);
+            if x.rows != rows {
+                return Err(TensorError::ShapeMismatch { op: "column concatenation", left: vec![rows, cols], right: x.shape() });
+            }
+            value.set_columns(start, x);
+            start += x.cols;
+        }
+        Ok(self.push(value, Op::ConcatColumns(parts.to_vec())))
+    }
+
+    // Gradient slot for var, zero-filled on first use
+    fn grad_slot<'g>(&self, grads: &'g mut [Option<Matrix<T>>], var: Var) -> &'g mut Matrix<T> {
+        let value = self.value(var);
+        grads[var.0].get_or_insert_with(|| self.ws.take(value.rows, value.cols))
+    }
+
+    // Propagate seed, the gradient of some scalar with respect to output, back
+    // to every value it depends on. Only leaf gradients are kept.
+    fn backward(&self, output: Var, seed: &Matrix<T>) -> Result<Gradients<T>, TensorError> {
+        println!("Running backward pass over {} tape values", output.0 + 1);
+        self.value(output).check_same_shape("backward seed", seed)?;
+        let mut grads: Vec<Option<Matrix<T>>> = (0..=output.0).map(|_| None).collect();
+        self.grad_slot(&mut grads, output).data.copy_from_slice(&seed.data);
+
+        for index in (0..=output.0).rev() {
+            let Some(grad) = grads[index].take() else { continue };
+            let node = &self.nodes[index];
+            match &node.op {
+                Op::Leaf => {
+                    grads[index] = Some(grad);
+                    continue;
+                }
+                &Op::MatMul(a, b) => {
+                    self.grad_slot(&mut grads, a).try_gemm(GemmOp::NT, &grad, self.value(b))?;
+                    self.grad_slot(&mut grads, b).try_gemm(GemmOp::TN, self.value(a), &grad)?;
+                }
+                &Op::MatMulTransposed(a, b) => {
+                    self.grad_slot(&mut grads, a).try_gemm(GemmOp::NN, &grad, self.value(b))?;
+                    self.grad_slot(&mut grads, b).try_gemm(GemmOp::TN, &grad, self.value(a))?;
+                }
+                &Op::Add(a, b) => {
+                    self.grad_slot(&mut grads, a).try_add_assign(&grad)?;
+                    self.grad_slot(&mut grads, b).try_add_assign(&grad)?;
+                }
+                &Op::AddRow(a, bias) => {
+                    self.grad_slot(&mut grads, a).try_add_assign(&grad)?;
+                    grad.sum_rows_into(self.grad_slot(&mut grads, bias));
+                }
+                &Op::Scale(a, scalar) => {
+                    self.grad_slot(&mut grads, a).try_axpy(scalar, &grad)?;
+                }
+                &Op::Relu(a) => {
+                    let d_a = self.grad_slot(&mut grads, a);
+                    for ((d, &g), &y) in d_a.data.iter_mut().zip(&grad.data).zip(&node.value.data) {
+                        if y > T::zero() {
+                            *d += g;
+                        }
+                    }
+                }
+                &Op::Softmax(a) => {
+                    softmax_backward(&node.value.data, &grad.data, &mut self.grad_slot(&mut grads, a).data, node.value.cols);
+                }
+                Op::LayerNorm { input, gamma, beta, normed, inv_std } => {
+                    // Three distinct slots are written at once, so lift them out
+                    let [mut d_input, mut d_gamma, mut d_beta] = [*input, *gamma, *beta].map(|var| {
+                        self.grad_slot(&mut grads, var);
+                        grads[var.0].take().unwrap()
+                    });
+                    layer_norm_backward(&grad, normed, inv_std, self.value(*gamma), &mut d_input, &mut d_gamma, &mut d_beta);
+                    for (var, slot) in [(*input, d_input), (*gamma, d_gamma), (*beta, d_beta)] {
+                        grads[var.0] = Some(slot);
+                    }
+                }
+                Op::Gather(table, ids) => {
+                    let d_table = self.grad_slot(&mut grads, *table);
+                    for (i, &id) in ids.iter().enumerate() {
+                        T::axpy(T::one(), grad.row(i), d_table.row_mut(id));
+                    }
+                }
+                &Op::Columns(a, start) => {
+                    let d_a = self.grad_slot(&mut grads, a);
+                    for i in 0..grad.rows {
+                        T::axpy(T::one(), grad.row(i), &mut d_a.row_mut(i)[start..start + grad.cols]);
+                    }
+                }
+                Op::ConcatColumns(parts) => {
+                    let mut start = 0;
+                    for &part in parts {
+                        let d_part = self.grad_slot(&mut grads, part);
+                        for i in 0..grad.rows {
+                            T::axpy(T::one(), &grad.row(i)[start..start + d_part.cols], d_part.row_mut(i));
+                        }
+                        start += d_part.cols;
+                    }
+                }
+            }
+            // Intermediate gradients are done with once passed on
+            self.ws.give(grad);
+        }
+        Ok(Gradients { grads })
+    }
+
+    // Hand every recorded value back to the workspace
+    fn release(self) {
+        for node in self.nodes {
+            if let Op::LayerNorm { normed, .. } = node.op {
+                self.ws.give(normed);
+            }
+            self.ws.give(node.value);
+        }
+    }
+}
+
+// SplitMix64 step: advance the state and return it scrambled, so nearby
+// seeds give unrelated outputs
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn fnv1a(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// xoshiro256** seeded through SplitMix64. Each consumer (weight init,
+// sampling, and later dropout or shuffling) draws from its own named stream
+// derived from one run seed, and split() hands out non-overlapping streams.
+#[derive(Clone)]
+struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        println!("Initializing RNG with seed: {}", seed);
+        let mut x = seed;
+        Rng { state: [splitmix64(&mut x), splitmix64(&mut x), splitmix64(&mut x), splitmix64(&mut x)] }
+    }
+
+    // The stream called name under run seed: FNV-1a of the name mixed into
+    // the scrambled seed. XORing the raw seed would let seeds that differ by
+    // the XOR of two name hashes swap streams.
+    fn stream(seed: u64, name: &str) -> Self {
+        println!("Deriving RNG stream '{}' from run seed {}", name, seed);
+        let mut state = seed;
+        Rng::new(splitmix64(&mut state) ^ fnv1a(name))
+    }
+
+    // Hand out the current stream and jump this one 2^128 draws ahead, so
+    // the two never overlap
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn split(&mut self) -> Rng {
+        const JUMP: [u64; 4] = [0x180ec6d33cfd0aba, 0xd5a61266f0c9392c, 0xa9582618e03fc9aa, 0x39abdc4529b1661c];
+        let child = self.clone();
+        let mut jumped = [0u64; 4];
+        for word in JUMP {
+            for bit in 0..64 {
+                if word & (1 << bit) != 0 {
+                    jumped.iter_mut().zip(self.state).for_each(|(j, s)| *j ^= s);
+                }
+                self.next();
+            }
+        }
+        self.state = jumped;
+        child
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    // Uniform in [0, 1) from the top 53 bits
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn uniform(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.next_f64()
+    }
+
+    // For dropout, which nothing applies yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn bernoulli(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+
+    // Index drawn with probability proportional to its weight
+    fn categorical(&mut self, weights: &[f64]) -> usize {
+        let target = self.next_f64() * weights.iter().sum::<f64>();
+        let mut cumulative = 0.0;
+        for (index, &weight) in weights.iter().enumerate() {
+            cumulative += weight;
+            if target < cumulative {
+                return index;
+            }
+        }
+        // Rounding can leave target just past the last cumulative sum
+        weights.iter().rposition(|&w| w > 0.0).unwrap_or(weights.len() - 1)
+    }
+
+    // Standard normal sample via the Box-Muller transform
+    fn next_gaussian(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    // Standard normal sample redrawn until it lies within two standard deviations
+    fn next_truncated_gaussian(&mut self) -> f64 {
+        loop {
+            let sample = self.next_gaussian();
+            if sample.abs() <= 2.0 {
+                return sample;
+            }
+        }
+    }
+}
+
+// How a weight matrix is filled. Layers compute input * weights, so fan-in
+// is the row count and fan-out the column count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Init {
+    // Uniform in [-limit, limit]
+    Uniform(f64),
+    // Xavier/Glorot: uniform with limit sqrt(6 / (fan_in + fan_out))
+    Xavier,
+    // Kaiming/He: normal with std sqrt(2 / fan_in), for layers feeding a ReLU
+    Kaiming,
+    // Normal with the given std, resampled beyond two standard deviations
+    TruncatedNormal(f64),
+}
+
+fn initialize_weights<T: Float>(matrix: &mut Matrix<T>, init: Init, rng: &mut Rng) {
+    println!("Initializing weights for matrix: {}x{} with {:?}", matrix.rows, matrix.cols, init);
+    let (fan_in, fan_out) = (matrix.rows as f64, matrix.cols as f64);
+    for value in matrix.data.iter_mut() {
+        let sample = match init {
+            Init::Uniform(limit) => rng.uniform(-limit, limit),
+            Init::Xavier => {
+                let limit = (6.0 / (fan_in + fan_out)).sqrt();
+                rng.uniform(-limit, limit)
+            }
+            Init::Kaiming => rng.next_gaussian() * (2.0 / fan_in).sqrt(),
+            Init::TruncatedNormal(std) => rng.next_truncated_gaussian() * std,
+        };
+        *value = T::from_f64(sample);
+    }
+}
+
+// Initialization for each kind of parameter. Biases always start at zero and
+// LayerNorm at gamma = 1, beta = 0.
+#[derive(Clone, Copy, Debug)]
+struct InitConfig {
+    embedding: Init,
+    // Attention projections and linear layers whose output is not rectified
+    linear: Init,
+    // Linear layers followed by a ReLU
+    relu: Init,
+    // Divide the projections that write into the residual stream (attention
+    // w_o and the block's w2) by sqrt(2 * num_blocks), as in GPT-2
+    scale_residual: bool,
+}
+
+impl InitConfig {
+    fn preset(name: &str) -> Option<Self> {
+        let normal = Init::TruncatedNormal(0.02);
+        match name {
+            "gpt2" => Some(InitConfig { embedding: normal, linear: normal, relu: normal, scale_residual: true }),
+            "xavier" => Some(InitConfig { embedding: normal, linear: Init::Xavier, relu: Init::Xavier, scale_residual: false }),
+            "kaiming" => Some(InitConfig { embedding: normal, linear: Init::Xavier, relu: Init::Kaiming, scale_residual: false }),
+            // The original scheme: every matrix uniform in [-1, 1]
+            "uniform" => Some(InitConfig { embedding: Init::Uniform(1.0), linear: Init::Uniform(1.0), relu: Init::Uniform(1.0), scale_residual: false }),
+            _ => None,
+        }
+    }
+}
+
+
+
+
+
+
+
+
+
+
+
+
+// Maps text to the token ids the model trains on. Implementations are
+// fitted on a corpus once and never change their ids afterwards.
+trait Tokenizer {
+    fn name(&self) -> &'static str;
+    fn encode(&self, text: &str) -> Vec<usize>;
+    fn decode(&self, ids: &[usize]) -> String;
+    // Printable form of a single token
+    fn id_to_token(&self, id: usize) -> String;
+    fn vocab_size(&self) -> usize;
+    // Id that generation must never sample, if the tokenizer has one
+    fn unknown_id(&self) -> Option<usize> {
+        None
+    }
+    // Full state in the text format load_tokenizer reads back
+    fn save(&self) -> String;
+}
+
+// Chooses a word vocabulary: <UNK> and the reserved special tokens first,
+// then words seen at least min_frequency times, most frequent first with ties
+// broken alphabetically, until the vocabulary holds max_size tokens
+struct VocabBuilder {
+    min_frequency: usize,
+    max_size: usize,
+    specials: Vec<String>,
+}
+
+impl Default for VocabBuilder {
+    fn default() -> Self {
+        VocabBuilder::new(3, 10000, Vec::new())
+    }
+}
+
+// Share of the corpus the chosen vocabulary can encode without <UNK>
+#[derive(Debug, PartialEq)]
+struct VocabCoverage {
+    kept_words: usize,
+    unique_words: usize,
+    covered_tokens: usize,
+    total_tokens: usize,
+}
+
+impl VocabBuilder {
+    // Repeated specials and <UNK>, which is always id 0, are dropped here, so
+    // a builder read back from a saved tokenizer reserves the same ids
+    fn new(min_frequency: usize, max_size: usize, specials: Vec<String>) -> Self {
+        let mut unique: Vec<String> = Vec::with_capacity(specials.len());
+        for special in specials {
+            if special != "<UNK>" && !unique.contains(&special) {
+                unique.push(special);
+            }
+        }
+        VocabBuilder { min_frequency, max_size, specials: unique }
+    }
+
+    fn build(&self, word_counts: &HashMap<String, usize>) -> (Vec<String>, VocabCoverage) {
+        let mut vocab = vec!["<UNK>".to_string()];
+        vocab.extend(self.specials.iter().cloned());
+        let reserved = vocab.len();
+
+        let mut candidates: Vec<(&String, &usize)> = word_counts.iter()
+            .filter(|&(word, &count)| count >= self.min_frequency && !vocab.contains(word))
+            .collect();
+        candidates.sort_by(|(a, count_a), (b, count_b)| count_b.cmp(count_a).then_with(|| a.cmp(b)));
+        let room = self.max_size.saturating_sub(reserved);
+        let kept = &candidates[..candidates.len().min(room)];
+        vocab.extend(kept.iter().map(|(word, _)| (*word).clone()));
+
+        let coverage = VocabCoverage {
+            kept_words: kept.len(),
+            unique_words: word_counts.len(),
+            covered_tokens: kept.iter().map(|(_, &count)| count).sum(),
+            total_tokens: word_counts.values().sum(),
+        };
+        (vocab, coverage)
+    }
+}
+
+// Lowercased whitespace-separated words, apart from the special tokens;
+// words left out of the vocabulary map to <UNK>
+struct WordTokenizer {
+    // Id -> word, and word -> id for encoding
+    vocab: Vec<String>,
+    ids: HashMap<String, usize>,
+    word_counts: HashMap<String, usize>,
+    // Ids of <UNK> and the reserved special tokens, fixed at fit time
+    unk_id: usize,
+    special_ids: HashMap<String, usize>,
+    builder: VocabBuilder,
+}
+
+impl WordTokenizer {
+    fn new(builder: VocabBuilder) -> Self {
+        println!("Creating new WordTokenizer: min_frequency={}, max_size={}, specials={:?}", builder.min_frequency, builder.max_size, builder.specials);
+        WordTokenizer {
+            vocab: Vec::new(),
+            ids: HashMap::new(),
+            word_counts: HashMap::new(),
+            unk_id: 0,
+            special_ids: HashMap::new(),
+            builder,
+        }
+    }
+
+    // Build the vocabulary from corpus. Encoding never changes it afterwards,
+    // so ids stay the ones the model was trained on.
+    fn fit(&mut self, corpus: &str) -> VocabCoverage {
+        println!("Fitting tokenizer on text of length: {}", corpus.len());
+
+        // Count words
+        self.word_counts.clear();
+        for word in Self::words(corpus, &self.builder.specials) {
+            *self.word_counts.entry(word).or_insert(0) += 1;
+        }
+
+        // Build vocabulary based on frequency
+        let (vocab, coverage) = self.builder.build(&self.word_counts);
+        self.set_vocab(vocab);
+
+        // Print statistics
+        println!("Vocabulary size: {}", self.vocab.len());
+        println!("Total unique words: {}", coverage.unique_words);
+        
+        let words_discarded = coverage.unique_words - coverage.kept_words;
+        println!("Words kept: {}, Words discarded: {}", coverage.kept_words, words_discarded);
+        println!("Vocabulary covers {:.2}% of {} corpus tokens",
+                 100.0 * coverage.covered_tokens as f64 / coverage.total_tokens.max(1) as f64, coverage.total_tokens);
+        
+        if coverage.kept_words > 0 {
+            println!("Most frequent kept words:");
+            for word in self.vocab.iter().skip(self.vocab.len() - coverage.kept_words).take(5) {
+                println!("  - {}", word);
+            }
+        }
+        
+        if words_discarded > 0 {
+            println!("Most frequent discarded words:");
+            let mut discarded_words: Vec<_> = self.word_counts.iter()
+                .filter(|(w, _)| !self.ids.contains_key(*w))
+                .collect();
+            discarded_words.sort_by(|(a, count_a), (b, count_b)| count_b.cmp(count_a).then_with(|| a.cmp(b)));
+            for (word, count) in discarded_words.into_iter().take(5) {
+                println!("  - {} (count: {})", word, count);
+            }
+        }
+        coverage
+    }
+
+    // Install vocab (<UNK> and the specials first) and rebuild the lookups
+    fn set_vocab(&mut self, vocab: Vec<String>) {
+        self.ids = vocab.iter().enumerate().map(|(id, word)| (word.clone(), id)).collect();
+        self.unk_id = self.ids["<UNK>"];
+        self.special_ids = std::iter::once("<UNK>").chain(self.builder.specials.iter().map(String::as_str))
+            .map(|token| (token.to_string(), self.ids[token]))
+            .collect();
+        self.vocab = vocab;
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn special_id(&self, token: &str) -> Option<usize> {
+        self.special_ids.get(token).copied()
+    }
+
+    fn load(saved: &mut SavedTokenizer) -> Result<Self, ParseError> {
+        let normalize = saved.field("normalize")?;
+        if normalize != "lowercase" {
+            return Err(SavedTokenizer::error(normalize, "the word tokenizer only supports lowercase normalization"));
+        }
+        let builder = VocabBuilder::new(
+            saved.count("min_frequency")?,
+            saved.count("max_size")?,
+            saved.field("specials")?.split_whitespace().map(String::from).collect(),
+        );
+        // The count comes from the file, so a damaged one must not size the allocation
+        let count = saved.count("tokens")?;
+        let mut vocab = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..count {
+            let word = saved.line()?;
+            if word.is_empty() || word.contains(char::is_whitespace) || !seen.insert(word) {
+                return Err(SavedTokenizer::error(word, "tokens must be unique and free of whitespace"));
+            }
+            vocab.push(word.to_string());
+        }
+        let reserved: Vec<&str> = std::iter::once("<UNK>").chain(builder.specials.iter().map(String::as_str)).collect();
+        if vocab.len() < reserved.len() || vocab.iter().zip(&reserved).any(|(word, special)| word != special) {
+            return Err(SavedTokenizer::error(&reserved.join(" "), "vocabulary must start with <UNK> and the special tokens"));
+        }
+        let mut tokenizer = WordTokenizer::new(builder);
+        tokenizer.set_vocab(vocab);
+        Ok(tokenizer)
+    }
+
+    // Special tokens are matched exactly before lowercasing, so "<BOS>" keeps its id
+    fn words<'a>(text: &'a str, specials: &'a [String]) -> impl Iterator<Item = String> + 'a {
+        text.split_whitespace().map(|word| if specials.iter().any(|special| special == word) { word.to_string() } else { word.to_lowercase() })
+    }
+}
+
+impl Tokenizer for WordTokenizer {
+    fn name(&self) -> &'static str {
+        "word"
+    }
+
+    // Map text to ids with the fitted vocabulary; unknown words become <UNK>
+    fn encode(&self, text: &str) -> Vec<usize> {
+        let tokens: Vec<usize> = Self::words(text, &self.builder.specials)
+            .map(|word| self.ids.get(&word).copied().unwrap_or(self.unk_id))
+            .collect();
+        println!("Encoded text of length {} into {} tokens", text.len(), tokens.len());
+        tokens
+    }
+
+    fn decode(&self, ids: &[usize]) -> String {
+        ids.iter().map(|&id| self.id_to_token(id)).collect::<Vec<_>>().join(" ")
+    }
+
+    fn id_to_token(&self, id: usize) -> String {
+        self.vocab.get(id).map_or("<UNK>", String::as_str).to_string()
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+
+    fn unknown_id(&self) -> Option<usize> {
+        Some(self.unk_id)
+    }
+
+    fn save(&self) -> String {
+        let mut text = format!("{}\nkind word\nnormalize lowercase\nmin_frequency {}\nmax_size {}\nspecials {}\ntokens {}\n",
+            TOKENIZER_FORMAT, self.builder.min_frequency, self.builder.max_size, self.builder.specials.join(" "), self.vocab.len());
+        for word in &self.vocab {
+            text.push_str(word);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+// Byte-pair encoding over raw UTF-8 bytes. The 256 byte values are the base
+// vocabulary, so any input encodes and decodes back exactly; each learned
+// merge adds the concatenation of two existing tokens.
+struct BpeTokenizer {
+    // Bytes of each token id
+    tokens: Vec<Vec<u8>>,
+    // Rank of each merged pair in learning order; merge i creates id 256 + i
+    ranks: HashMap<(usize, usize), usize>,
+}
+
+impl BpeTokenizer {
+    fn from_merges(merges: Vec<(usize, usize)>) -> Self {
+        let mut tokens: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        for &(a, b) in &merges {
+            let merged = [tokens[a].as_slice(), tokens[b].as_slice()].concat();
+            tokens.push(merged);
+        }
+        let ranks = merges.iter().enumerate().map(|(rank, &pair)| (pair, rank)).collect();
+        BpeTokenizer { tokens, ranks }
+    }
+
+    // Learn merges from corpus until the vocabulary reaches vocab_size or no
+    // pair occurs twice. Ties go to the smallest pair of ids, so training is
+    // deterministic.
+    fn train(corpus: &str, vocab_size: usize) -> Self {
+        println!("Training BPE tokenizer on text of length {} to {} tokens", corpus.len(), vocab_size);
+        let mut chunk_counts: HashMap<&str, usize> = HashMap::new();
+        for chunk in Self::chunks(corpus) {
+            *chunk_counts.entry(chunk).or_insert(0) += 1;
+        }
+        let mut chunks: Vec<(Vec<usize>, usize)> = chunk_counts.into_iter()
+            .map(|(chunk, count)| (chunk.bytes().map(usize::from).collect(), count))
+            .collect();
+
+        let mut merges = Vec::new();
+        while 256 + merges.len() < vocab_size {
+            let mut pair_counts: HashMap<(usize, usize), usize> = HashMap::new();
+            for (ids, count) in &chunks {
+                for pair in ids.windows(2) {
+                    *pair_counts.entry((pair[0], pair[1])).or_insert(0) += count;
+                }
+            }
+            let best = pair_counts.into_iter().max_by_key(|&(pair, count)| (count, std::cmp::Reverse(pair)));
+            let Some((pair, count)) = best.filter(|&(_, count)| count >= 2) else { break };
+            let id = 256 + merges.len();
+            if merges.len() % 100 == 0 {
+                println!("BPE merge {}: {:?} -> {} ({} occurrences)", merges.len(), pair, id, count);
+            }
+            chunks.iter_mut().for_each(|(ids, _)| Self::merge(ids, pair, id));
+            merges.push(pair);
+        }
+        println!("Learned {} BPE merges", merges.len());
+        Self::from_merges(merges)
+    }
+
+    // Split text into runs of whitespace followed by non-whitespace, so a word
+    // keeps its leading space and merges never cross word boundaries
+    fn chunks(text: &str) -> impl Iterator<Item = &str> + '_ {
+        let mut rest = text;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let word_start = rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+            let end = rest[word_start..].find(char::is_whitespace).map_or(rest.len(), |i| word_start + i);
+            let (chunk, tail) = rest.split_at(end);
+            rest = tail;
+            Some(chunk)
+        })
+    }
+
+    fn merge(ids: &mut Vec<usize>, pair: (usize, usize), id: usize) {
+        let mut read = 0;
+        let mut write = 0;
+        while read < ids.len() {
+            if read + 1 < ids.len() && (ids[read], ids[read + 1]) == pair {
+                ids[write] = id;
+                read += 2;
+            } else {
+                ids[write] = ids[read];
+                read += 1;
+            }
+            write += 1;
+        }
+        ids.truncate(write);
+    }
+
+    // Apply learned merges to one chunk, earliest-learned first
+    fn encode_chunk(&self, chunk: &str) -> Vec<usize> {
+        let mut ids: Vec<usize> = chunk.bytes().map(usize::from).collect();
+        while let Some((rank, pair)) = ids.windows(2)
+            .filter_map(|pair| self.ranks.get(&(pair[0], pair[1])).map(|&rank| (rank, (pair[0], pair[1]))))
+            .min()
+        {
+            Self::merge(&mut ids, pair, 256 + rank);
+        }
+        ids
+    }
+
+    fn load(saved: &mut SavedTokenizer) -> Result<Self, ParseError> {
+        let normalize = saved.field("normalize")?;
+        if normalize != "none" {
+            return Err(SavedTokenizer::error(normalize, "the BPE tokenizer does not normalize text"));
+        }
+        let count = saved.count("merges")?;
+        let mut merges = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for i in 0..count {
+            let line = saved.line()?;
+            let pair = line.split_once(' ').and_then(|(a, b)| Some((a.parse::<usize>().ok()?, b.parse::<usize>().ok()?)));
+            match pair {
+                // A merge can only combine bytes and earlier merges
+                Some((a, b)) if a < 256 + i && b < 256 + i => merges.push((a, b)),
+                _ => return Err(SavedTokenizer::error(line, format!("merge {} must be two ids below {}", i, 256 + i))),
+            }
+            // A repeated pair would leave its first token unreachable
+            if !seen.insert(merges[i]) {
+                return Err(SavedTokenizer::error(line, format!("merge on line {} repeats an earlier merge", saved.line_number)));
+            }
+        }
+        Ok(Self::from_merges(merges))
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn name(&self) -> &'static str {
+        "bpe"
+    }
+
+    fn encode(&self, text: &str) -> Vec<usize> {
+        // Words repeat, so encode each distinct chunk once
+        let mut encoded: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut ids = Vec::new();
+        for chunk in Self::chunks(text) {
+            ids.extend_from_slice(encoded.entry(chunk).or_insert_with(|| self.encode_chunk(chunk)));
+        }
+        println!("Encoded text of length {} into {} tokens", text.len(), ids.len());
+        ids
+    }
+
+    fn decode(&self, ids: &[usize]) -> String {
+        let bytes: Vec<u8> = ids.iter().filter_map(|&id| self.tokens.get(id)).flatten().copied().collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn id_to_token(&self, id: usize) -> String {
+        self.decode(&[id])
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn save(&self) -> String {
+        let mut merges = vec![(0, 0); self.ranks.len()];
+        for (&pair, &rank) in &self.ranks {
+            merges[rank] = pair;
+        }
+        let mut text = format!("{}\nkind bpe\nnormalize none\nmerges {}\n", TOKENIZER_FORMAT, merges.len());
+        for (a, b) in merges {
+            text.push_str(&format!("{} {}\n", a, b));
+        }
+        text
+    }
+}
+
+// The tokenizer named by RUSTFORMER_TOKENIZER, fitted on corpus: bpe (the
+// default) learns merges up to RUSTFORMER_BPE_VOCAB tokens (512), word keeps
+// the RUSTFORMER_VOCAB_SIZE most frequent words (10000) seen at least
+// RUSTFORMER_VOCAB_MIN_FREQ times (3)
+fn build_tokenizer(name: &str, corpus: &str) -> Box<dyn Tokenizer> {
+    let env_usize = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<usize>().ok());
+    match name {
+        "bpe" => {
+            let vocab_size = env_usize("RUSTFORMER_BPE_VOCAB").unwrap_or(512);
+            Box::new(BpeTokenizer::train(corpus, vocab_size))
+        }
+        "word" => {
+            let defaults = VocabBuilder::default();
+            let mut tokenizer = WordTokenizer::new(VocabBuilder::new(
+                env_usize("RUSTFORMER_VOCAB_MIN_FREQ").unwrap_or(defaults.min_frequency),
+                env_usize("RUSTFORMER_VOCAB_SIZE").unwrap_or(defaults.max_size),
+                defaults.specials,
+            ));
+            tokenizer.fit(corpus);
+            Box::new(tokenizer)
+        }
+        other => panic!("Unknown tokenizer '{}', expected one of bpe, word", other),
+    }
+}
+
+// First line of a saved tokenizer; bump the version when the format changes
+const TOKENIZER_FORMAT: &str = "rustformer-tokenizer v1";
+
+// Reads a saved tokenizer line by line: "key value" fields in a fixed order,
+// then one vocabulary entry or merge per line
+struct SavedTokenizer<'a> {
+    lines: std::str::Lines<'a>,
+    // 1-based number of the last line read
+    line_number: usize,
+}
+
+impl<'a> SavedTokenizer<'a> {
+    fn error(input: &str, reason: impl Into<String>) -> ParseError {
+        ParseError { what: "saved tokenizer", input: input.to_string(), reason: reason.into() }
+    }
+
+    fn line(&mut self) -> Result<&'a str, ParseError> {
+        let line = self.lines.next().ok_or_else(|| Self::error("", "unexpected end of file"))?;
+        self.line_number += 1;
+        Ok(line)
+    }
+
+    fn field(&mut self, key: &str) -> Result<&'a str, ParseError> {
+        let line = self.line()?;
+        match line.split_once(' ') {
+            Some((name, value)) if name == key => Ok(value),
+            _ if line == key => Ok(""),
+            _ => Err(Self::error(line, format!("expected '{}'", key))),
+        }
+    }
+
+    fn count(&mut self, key: &str) -> Result<usize, ParseError> {
+        let value = self.field(key)?;
+        value.parse().map_err(|_| Self::error(value, format!("{} must be a count", key)))
+    }
+}
+
+// Rebuild a tokenizer from save(), with exactly the ids it had when saved
+fn load_tokenizer(text: &str) -> Result<Box<dyn Tokenizer>, ParseError> {
+    let mut saved = SavedTokenizer { lines: text.lines(), line_number: 0 };
+    let header = saved.line()?;
+    if header != TOKENIZER_FORMAT {
+        return Err(SavedTokenizer::error(header, format!("expected '{}'", TOKENIZER_FORMAT)));
+    }
+    let tokenizer: Box<dyn Tokenizer> = match saved.field("kind")? {
+        "word" => Box::new(WordTokenizer::load(&mut saved)?),
+        "bpe" => Box::new(BpeTokenizer::load(&mut saved)?),
+        other => return Err(SavedTokenizer::error(other, "unknown tokenizer kind")),
+    };
+    match saved.lines.find(|line| !line.is_empty()) {
+        Some(extra) => Err(SavedTokenizer::error(extra, "unexpected line after the tokenizer")),
+        None => Ok(tokenizer),
+    }
+}
+
+
+
+
+
+
+
+
+
+
+
+
+struct Embedding<T> {
+    vocab_size: usize,
+    embedding_dim: usize,
+    embeddings: Param<T>,
+    cache: Option<Vec<usize>>,
+    training: bool,
+}
+
+impl<T: Float> Embedding<T> {
+    fn new(vocab_size: usize, embedding_dim: usize) -> Self {
+        println!("Creating Embedding with vocab_size: {}, embedding_dim: {}", vocab_size, embedding_dim);
+        let embeddings = Param::new(vocab_size, embedding_dim);
+        Embedding {
+            vocab_size,
+            embedding_dim,
+            embeddings,
+            cache: None,
+            training: true,
+        }
+    }
+
+
+    #[cfg(test)]
+    fn forward_tape(&self, tape: &mut Tape<T>, input: &[usize]) -> Result<Var, TensorError> {
+        let embeddings = tape.param(&self.embeddings.value);
+        tape.gather(embeddings, input)
+    }
+}
+
+impl<T: Float> Module<T> for Embedding<T> {
+    type Input = [usize];
+    type InputGrad = ();
+
+    // Training mode remembers the token ids for backward
+    fn forward(&mut self, input: &[usize], ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("Embedding forward pass with input length: {}", input.len());
+        self.cache = None;
+        if let Some(&token) = input.iter().find(|&&token| token >= self.vocab_size) {
+            return Err(TensorError::OutOfBounds { index: vec![token], shape: vec![self.vocab_size] });
+        }
+        let mut result = ws.take(input.len(), self.embedding_dim);
+        for (i, &token) in input.iter().enumerate() {
+            result.row_mut(i).copy_from_slice(self.embeddings.value.row(token));
+        }
+        if self.training {
+            self.cache = Some(input.to_vec());
+        }
+        Ok(result)
+    }
+
+    // Scatter-add each row of the output gradient into its token's row
+    fn backward(&mut self, gradients: &Matrix<T>, _: &Workspace<T>) -> Result<(), TensorError> {
+        println!("Embedding backward pass");
+        let input = self.cache.take().ok_or(TensorError::MissingCache { layer: "Embedding" })?;
+        if gradients.rows != input.len() || gradients.cols != self.embedding_dim {
+            return Err(TensorError::ShapeMismatch { op: "embedding backward", left: gradients.shape(), right: vec![input.len(), self.embedding_dim] });
+        }
+        for (i, &token) in input.iter().enumerate() {
+            T::axpy(T::one(), gradients.row(i), self.embeddings.grad.row_mut(token));
+        }
+        Ok(())
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)> {
+        vec![("embeddings".to_string(), &self.embeddings)]
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)> {
+        vec![("embeddings".to_string(), &mut self.embeddings)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Write the sinusoidal encoding for encoding.rows positions into encoding
+fn positional_encoding<T: Float>(encoding: &mut Matrix<T>) {
+    let (seq_len, embedding_dim) = (encoding.rows, encoding.cols);
+    println!("Generating positional encoding: seq_len={}, embedding_dim={}", seq_len, embedding_dim);
+    for pos in 0..seq_len {
+        for i in 0..embedding_dim {
+            if i % 2 == 0 {
+                encoding.set(pos, i, T::from_f64((pos as f64 / 10000_f64.powf(i as f64 / embedding_dim as f64)).sin()));
+            } else {
+                encoding.set(pos, i, T::from_f64((pos as f64 / 10000_f64.powf((i - 1) as f64 / embedding_dim as f64)).cos()));
+            }
+        }
+    }
+}
+
+
+
+
+
+
+
+
+
+
+
+
+
+// Activations from a training-mode MultiHeadAttention forward that backward
+// needs. Per-head values are laid out as [batch, heads, seq, head_dim] and the
+// attention probabilities as [batch, heads, seq, seq].
+struct AttentionCache<T> {
+    input: Matrix<T>,
+    q: Tensor<T>,
+    k: Tensor<T>,
+    v: Tensor<T>,
+    probs: Tensor<T>,
+    concat: Matrix<T>,
+}
+
+impl<T: Float> AttentionCache<T> {
+    fn release(self, ws: &Workspace<T>) {
+        for buffer in [self.q, self.k, self.v, self.probs] {
+            ws.give_tensor(buffer);
+        }
+        ws.give(self.input);
+        ws.give(self.concat);
+    }
+}
+
+struct MultiHeadAttention<T> {
+    heads: usize,
+    dim: usize,
+    head_dim: usize,
+    w_q: Param<T>,
+    w_k: Param<T>,
+    w_v: Param<T>,
+    w_o: Param<T>,
+    cache: Option<AttentionCache<T>>,
+    training: bool,
+}
+
+impl<T: Float> MultiHeadAttention<T> {
+    fn new(heads: usize, dim: usize) -> Self {
+        println!("Creating MultiHeadAttention: heads={}, dim={}", heads, dim);
+        assert!(dim.is_multiple_of(heads), "dim must be divisible by heads");
+        let head_dim = dim / heads;
+        let w_q = Param::new(dim, dim);
+        let w_k = Param::new(dim, dim);
+        let w_v = Param::new(dim, dim);
+        let w_o = Param::new(dim, dim);
+        MultiHeadAttention { heads, dim, head_dim, w_q, w_k, w_v, w_o, cache: None, training: true }
+    }
+
+    // Copy a [batch * seq, dim] projection into [batch, heads, seq, head_dim]
+    fn split_heads(&self, x: &Matrix<T>, batch: usize, ws: &Workspace<T>) -> Result<Tensor<T>, TensorError> {
+        let heads = x.view().reshape(&[batch, x.rows / batch, self.heads, self.head_dim])?.permute(&[0, 2, 1, 3])?;
+        let mut split = ws.take_tensor(&heads.shape);
+        heads.copy_into(&mut split.data)?;
+        Ok(split)
+    }
+
+    // Copy [batch, heads, seq, head_dim] back to [batch * seq, dim]
+    fn merge_heads(&self, x: &Tensor<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        let rows = x.view().permute(&[0, 2, 1, 3])?;
+        let mut merged = ws.take(x.shape[0] * x.shape[2], self.dim);
+        rows.copy_into(&mut merged.data)?;
+        Ok(merged)
+    }
+
+    // Self-attention over a contiguous [batch, seq, dim] input. Every head of
+    // every sequence runs in one batched matmul over [batch, heads, seq,
+    // head_dim]. Training mode keeps q, k, v and the attention probabilities
+    // for backward; eval mode hands them back.
+    fn forward_batched(&mut self, input: &TensorView<T>, ws: &Workspace<T>) -> Result<Tensor<T>, TensorError> {
+        println!("MultiHeadAttention batched forward pass: {:?}", input.shape);
+        if input.shape.len() != 3 || input.shape[2] != self.dim || input.numel() == 0 {
+            return Err(TensorError::ShapeMismatch { op: "attention input", left: input.shape.clone(), right: vec![self.dim] });
+        }
+        if let Some(stale) = self.cache.take() {
+            stale.release(ws);
+        }
+        let (batch, seq_len) = (input.shape[0], input.shape[1]);
+        let flat = input.reshape(&[batch * seq_len, self.dim])?;
+        let project = |weights: &Matrix<T>| {
+            let mut projected = ws.take(batch * seq_len, self.dim);
+            flat.matmul_into(&weights.view(), &mut projected.data)?;
+            let heads = self.split_heads(&projected, batch, ws);
+            ws.give(projected);
+            heads
+        };
+        let (q, k, v) = (project(&self.w_q.value)?, project(&self.w_k.value)?, project(&self.w_v.value)?);
+
+        // Scores from q and the permuted k, which the NT kernel reads in place
+        let mut probs = ws.take_tensor(&[batch, self.heads, seq_len, seq_len]);
+        q.view().matmul_into(&k.view().permute(&[0, 1, 3, 2])?, &mut probs.data)?;
+        probs.scale_inplace(T::from_f64(1.0 / (self.head_dim as f64).sqrt()));
+        probs.softmax_last_axis();
+
+        let mut context = ws.take_tensor(&q.shape);
+        probs.view().matmul_into(&v.view(), &mut context.data)?;
+        let concat = self.merge_heads(&context, ws)?;
+        ws.give_tensor(context);
+        println!("MultiHeadAttention output shape: {}x{}", concat.rows, concat.cols);
+        let output = ws.gemm(GemmOp::NN, &concat, &self.w_o.value)?;
+        if self.training {
+            let mut cached_input = ws.take(batch * seq_len, self.dim);
+            flat.copy_into(&mut cached_input.data)?;
+            self.cache = Some(AttentionCache { input: cached_input, q, k, v, probs, concat });
+        } else {
+            for buffer in [q, k, v, probs] {
+                ws.give_tensor(buffer);
+            }
+            ws.give(concat);
+        }
+        Tensor::from_matrix(output).reshape(&[batch, seq_len, self.dim])
+    }
+
+    // Self-attention built from tape ops, so gradients reach the projections
+    // through the softmax of every head
+    #[cfg(test)]
+    fn forward_tape(&self, tape: &mut Tape<T>, input: Var) -> Result<Var, TensorError> {
+        let (w_q, w_k, w_v, w_o) = (tape.param(&self.w_q.value), tape.param(&self.w_k.value), tape.param(&self.w_v.value), tape.param(&self.w_o.value));
+        let q = tape.matmul(input, w_q)?;
+        let k = tape.matmul(input, w_k)?;
+        let v = tape.matmul(input, w_v)?;
+
+        let scale = T::from_f64(1.0 / (self.head_dim as f64).sqrt());
+        let mut head_outputs = Vec::with_capacity(self.heads);
+        for h in 0..self.heads {
+            let start = h * self.head_dim;
+            let q_h = tape.columns(q, start, self.head_dim)?;
+            let k_h = tape.columns(k, start, self.head_dim)?;
+            let v_h = tape.columns(v, start, self.head_dim)?;
+            let scores = tape.matmul_transposed(q_h, k_h)?;
+            let scores = tape.scale(scores, scale);
+            let attention = tape.softmax(scores);
+            head_outputs.push(tape.matmul(attention, v_h)?);
+        }
+        let concat_output = tape.concat_columns(&head_outputs)?;
+        tape.matmul(concat_output, w_o)
+    }
+}
+
+impl<T: Float> Module<T> for MultiHeadAttention<T> {
+    type Input = Matrix<T>;
+    type InputGrad = Matrix<T>;
+
+    // Self-attention over one [seq, dim] sequence, as a batch of one
+    fn forward(&mut self, input: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("MultiHeadAttention forward pass");
+        let output = self.forward_batched(&input.view().reshape(&[1, input.rows, input.cols])?, ws)?;
+        Ok(output.into_matrix())
+    }
+
+    // Gradients take the same [batch, heads, seq, head_dim] layout as forward,
+    // so the input gradient is [batch * seq, dim] for any batch size
+    fn backward(&mut self, gradients: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("MultiHeadAttention backward pass");
+        let cache = self.cache.take().ok_or(TensorError::MissingCache { layer: "MultiHeadAttention" })?;
+        if let Err(error) = cache.concat.check_same_shape("attention backward", gradients) {
+            cache.release(ws);
+            return Err(error);
+        }
+        let (batch, seq_len) = (cache.q.shape[0], cache.q.shape[2]);
+
+        // Output projection
+        self.w_o.grad.try_gemm(GemmOp::TN, &cache.concat, gradients)?;
+        let d_concat = ws.gemm(GemmOp::NT, gradients, &self.w_o.value)?;
+        let d_out = self.split_heads(&d_concat, batch, ws)?;
+        ws.give(d_concat);
+
+        // Back through every head at once: out = P v, P = softmax(scale * q k^T).
+        // Permuted operands are read in place by the NT and TN kernels.
+        let transposed = [0, 1, 3, 2];
+        let [mut d_q, mut d_k, mut d_v] = [(); 3].map(|_| ws.take_tensor(&cache.q.shape));
+        let [mut d_probs, mut d_scores] = [(); 2].map(|_| ws.take_tensor(&cache.probs.shape));
+        cache.probs.view().permute(&transposed)?.matmul_into(&d_out.view(), &mut d_v.data)?;
+        d_out.view().matmul_into(&cache.v.view().permute(&transposed)?, &mut d_probs.data)?;
+        softmax_backward(&cache.probs.data, &d_probs.data, &mut d_scores.data, seq_len);
+        d_scores.scale_inplace(T::from_f64(1.0 / (self.head_dim as f64).sqrt()));
+        d_scores.view().matmul_into(&cache.k.view(), &mut d_q.data)?;
+        d_scores.view().permute(&transposed)?.matmul_into(&cache.q.view(), &mut d_k.data)?;
+        for buffer in [d_out, d_probs, d_scores] {
+            ws.give_tensor(buffer);
+        }
+        let (d_q_heads, d_k_heads, d_v_heads) = (d_q, d_k, d_v);
+        let (d_q, d_k, d_v) = (self.merge_heads(&d_q_heads, ws)?, self.merge_heads(&d_k_heads, ws)?, self.merge_heads(&d_v_heads, ws)?);
+        for buffer in [d_q_heads, d_k_heads, d_v_heads] {
+            ws.give_tensor(buffer);
+        }
+
+        // Input projections; the input feeds all three, so its gradient sums
+        self.w_q.grad.try_gemm(GemmOp::TN, &cache.input, &d_q)?;
+        self.w_k.grad.try_gemm(GemmOp::TN, &cache.input, &d_k)?;
+        self.w_v.grad.try_gemm(GemmOp::TN, &cache.input, &d_v)?;
+        let mut d_input = ws.gemm(GemmOp::NT, &d_q, &self.w_q.value)?;
+        d_input.try_gemm(GemmOp::NT, &d_k, &self.w_k.value)?;
+        d_input.try_gemm(GemmOp::NT, &d_v, &self.w_v.value)?;
+
+        for buffer in [d_q, d_k, d_v] {
+            ws.give(buffer);
+        }
+        cache.release(ws);
+        Ok(d_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)> {
+        vec![("w_q".to_string(), &self.w_q), ("w_k".to_string(), &self.w_k), ("w_v".to_string(), &self.w_v), ("w_o".to_string(), &self.w_o)]
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)> {
+        vec![("w_q".to_string(), &mut self.w_q), ("w_k".to_string(), &mut self.w_k), ("w_v".to_string(), &mut self.w_v), ("w_o".to_string(), &mut self.w_o)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+// Activations from a training-mode FeedForward forward that backward needs
+struct FeedForwardCache<T> {
+    input: Matrix<T>,
+    hidden: Matrix<T>,
+}
+
+struct FeedForward<T> {
+    output_dim: usize,
+    w1: Param<T>,
+    w2: Param<T>,
+    b1: Param<T>,
+    b2: Param<T>,
+    cache: Option<FeedForwardCache<T>>,
+    training: bool,
+}
+
+impl<T: Float> FeedForward<T> {
+    fn new(input_dim: usize, output_dim: usize) -> Self {
+        println!("Creating FeedForward: input_dim={}, output_dim={}", input_dim, output_dim);
+        let w1 = Param::new(input_dim, input_dim * 4);
+        let w2 = Param::new(input_dim * 4, output_dim);
+        let b1 = Param::new(1, input_dim * 4);
+        let b2 = Param::new(1, output_dim);
+        FeedForward { output_dim, w1, w2, b1, b2, cache: None, training: true }
+    }
+
+    // ReLU(input * w1 + b1)
+    fn hidden(&self, input: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        let mut hidden = ws.gemm(GemmOp::NN, input, &self.w1.value)?;
+        for i in 0..hidden.rows {
+            for (value, &bias) in hidden.row_mut(i).iter_mut().zip(self.b1.value.row(0)) {
+                *value = (*value + bias).max(T::zero()); // ReLU activation
+            }
+        }
+        Ok(hidden)
+    }
+
+    // hidden * w2 + b2
+    fn project(&self, hidden: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        let mut output = ws.gemm(GemmOp::NN, hidden, &self.w2.value)?;
+        for i in 0..output.rows {
+            for (value, &bias) in output.row_mut(i).iter_mut().zip(self.b2.value.row(0)) {
+                *value += bias;
+            }
+        }
+        println!("FeedForward output shape: {}x{}", output.rows, output.cols);
+        Ok(output)
+    }
+
+    #[cfg(test)]
+    fn forward_tape(&self, tape: &mut Tape<T>, input: Var) -> Result<Var, TensorError> {
+        let (w1, b1, w2, b2) = (tape.param(&self.w1.value), tape.param(&self.b1.value), tape.param(&self.w2.value), tape.param(&self.b2.value));
+        let hidden = tape.matmul(input, w1)?;
+        let hidden = tape.add_row(hidden, b1)?;
+        let hidden = tape.relu(hidden);
+        let output = tape.matmul(hidden, w2)?;
+        tape.add_row(output, b2)
+    }
+}
+
+impl<T: Float> Module<T> for FeedForward<T> {
+    type Input = Matrix<T>;
+    type InputGrad = Matrix<T>;
+
+    // Training mode keeps the input and hidden activations for backward
+    fn forward(&mut self, input: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("FeedForward forward pass");
+        if let Some(stale) = self.cache.take() {
+            ws.give(stale.input);
+            ws.give(stale.hidden);
+        }
+        let hidden = self.hidden(input, ws)?;
+        let output = self.project(&hidden, ws)?;
+        if !self.training {
+            ws.give(hidden);
+            return Ok(output);
+        }
+        let mut cached_input = ws.take(input.rows, input.cols);
+        cached_input.data.copy_from_slice(&input.data);
+        self.cache = Some(FeedForwardCache { input: cached_input, hidden });
+        Ok(output)
+    }
+
+    fn backward(&mut self, gradients: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("FeedForward backward pass");
+        let FeedForwardCache { input, hidden } = self.cache.take().ok_or(TensorError::MissingCache { layer: "FeedForward" })?;
+        if gradients.rows != hidden.rows || gradients.cols != self.output_dim {
+            return Err(TensorError::ShapeMismatch { op: "feed-forward backward", left: gradients.shape(), right: vec![hidden.rows, self.output_dim] });
+        }
+
+        // Second layer
+        gradients.sum_rows_into(&mut self.b2.grad);
+        self.w2.grad.try_gemm(GemmOp::TN, &hidden, gradients)?;
+        let mut d_hidden = ws.gemm(GemmOp::NT, gradients, &self.w2.value)?;
+
+        // ReLU passes gradient only where its output was positive
+        for (d, &h) in d_hidden.data.iter_mut().zip(&hidden.data) {
+            if h <= T::zero() {
+                *d = T::zero();
+            }
+        }
+
+        // First layer
+        d_hidden.sum_rows_into(&mut self.b1.grad);
+        self.w1.grad.try_gemm(GemmOp::TN, &input, &d_hidden)?;
+        let d_input = ws.gemm(GemmOp::NT, &d_hidden, &self.w1.value)?;
+
+        for buffer in [input, hidden, d_hidden] {
+            ws.give(buffer);
+        }
+        Ok(d_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)> {
+        vec![("w1".to_string(), &self.w1), ("b1".to_string(), &self.b1), ("w2".to_string(), &self.w2), ("b2".to_string(), &self.b2)]
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)> {
+        vec![("w1".to_string(), &mut self.w1), ("b1".to_string(), &mut self.b1), ("w2".to_string(), &mut self.w2), ("b2".to_string(), &mut self.b2)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+// Normalized rows and their 1 / std from a training-mode LayerNorm forward
+struct LayerNormCache<T> {
+    normed: Matrix<T>,
+    inv_std: Vec<T>,
+}
+
+struct LayerNorm<T> {
+    dim: usize,
+    gamma: Param<T>,
+    beta: Param<T>,
+    cache: Option<LayerNormCache<T>>,
+    training: bool,
+}
+
+impl<T: Float> LayerNorm<T> {
+    fn new(dim: usize) -> Self {
+        println!("Creating LayerNorm: dim={}", dim);
+        let mut gamma = Param::new(1, dim);
+        gamma.value.data.fill(T::one());
+        let beta = Param::new(1, dim);
+        LayerNorm { dim, gamma, beta, cache: None, training: true }
+    }
+
+    fn check_input(&self, input: &Matrix<T>) -> Result<(), TensorError> {
+        if input.cols != self.dim {
+            return Err(TensorError::ShapeMismatch { op: "layer norm", left: input.shape(), right: vec![input.rows, self.dim] });
+        }
+        Ok(())
+    }
+
+    // gamma * normed + beta
+    fn affine(&self, normed: &Matrix<T>, ws: &Workspace<T>) -> Matrix<T> {
+        let mut output = ws.take(normed.rows, self.dim);
+        for i in 0..normed.rows {
+            for ((y, &n), (&g, &b)) in output.row_mut(i).iter_mut().zip(normed.row(i)).zip(self.gamma.value.row(0).iter().zip(self.beta.value.row(0))) {
+                *y = g * n + b;
+            }
+        }
+        println!("LayerNorm output shape: {}x{}", output.rows, output.cols);
+        output
+    }
+
+    #[cfg(test)]
+    fn forward_tape(&self, tape: &mut Tape<T>, input: Var) -> Result<Var, TensorError> {
+        let (gamma, beta) = (tape.param(&self.gamma.value), tape.param(&self.beta.value));
+        tape.layer_norm(input, gamma, beta)
+    }
+}
+
+impl<T: Float> Module<T> for LayerNorm<T> {
+    type Input = Matrix<T>;
+    type InputGrad = Matrix<T>;
+
+    // Training mode keeps the normalized input and 1 / std for backward
+    fn forward(&mut self, input: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("LayerNorm forward pass");
+        self.check_input(input)?;
+        if let Some(stale) = self.cache.take() {
+            ws.give(stale.normed);
+        }
+        let mut normed = ws.take(input.rows, self.dim);
+        let inv_std = normalize_rows(input, &mut normed);
+        let output = self.affine(&normed, ws);
+        if self.training {
+            self.cache = Some(LayerNormCache { normed, inv_std });
+        } else {
+            ws.give(normed);
+        }
+        Ok(output)
+    }
+
+    fn backward(&mut self, gradients: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("LayerNorm backward pass");
+        let LayerNormCache { normed, inv_std } = self.cache.take().ok_or(TensorError::MissingCache { layer: "LayerNorm" })?;
+        normed.check_same_shape("layer norm backward", gradients)?;
+        let mut d_input = ws.take(normed.rows, self.dim);
+        layer_norm_backward(gradients, &normed, &inv_std, &self.gamma.value, &mut d_input, &mut self.gamma.grad, &mut self.beta.grad);
+        ws.give(normed);
+        Ok(d_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)> {
+        vec![("gamma".to_string(), &self.gamma), ("beta".to_string(), &self.beta)]
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)> {
+        vec![("gamma".to_string(), &mut self.gamma), ("beta".to_string(), &mut self.beta)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+struct TransformerBlock<T> {
+    attention: MultiHeadAttention<T>,
+    feed_forward: FeedForward<T>,
+    norm1: LayerNorm<T>,
+    norm2: LayerNorm<T>,
+}
+
+impl<T: Float> TransformerBlock<T> {
+    fn new(heads: usize, dim: usize) -> Self {
+        println!("Creating TransformerBlock: heads={}, dim={}", heads, dim);
+        TransformerBlock {
+            attention: MultiHeadAttention::new(heads, dim),
+            feed_forward: FeedForward::new(dim, dim),
+            norm1: LayerNorm::new(dim),
+            norm2: LayerNorm::new(dim),
+        }
+    }
+
+    #[cfg(test)]
+    fn forward_tape(&self, tape: &mut Tape<T>, input: Var) -> Result<Var, TensorError> {
+        let attention_output = self.attention.forward_tape(tape, input)?;
+        let residual = tape.add(attention_output, input)?;
+        let normed_attention_output = self.norm1.forward_tape(tape, residual)?;
+        let feed_forward_output = self.feed_forward.forward_tape(tape, normed_attention_output)?;
+        let residual = tape.add(feed_forward_output, normed_attention_output)?;
+        self.norm2.forward_tape(tape, residual)
+    }
+}
+
+impl<T: Float> Module<T> for TransformerBlock<T> {
+    type Input = Matrix<T>;
+    type InputGrad = Matrix<T>;
+
+    // Each sublayer's Module::forward, so training mode leaves them holding
+    // the state backward needs. Residual sums reuse the sublayer output buffers.
+    fn forward(&mut self, input: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("TransformerBlock forward pass");
+        let mut attention_output = self.attention.forward(input, ws)?;
+        attention_output.try_add_assign(input)?;
+        let normed_attention_output = self.norm1.forward(&attention_output, ws)?;
+        ws.give(attention_output);
+        let mut feed_forward_output = self.feed_forward.forward(&normed_attention_output, ws)?;
+        feed_forward_output.try_add_assign(&normed_attention_output)?;
+        ws.give(normed_attention_output);
+        let output = self.norm2.forward(&feed_forward_output, ws)?;
+        ws.give(feed_forward_output);
+        println!("TransformerBlock output shape: {}x{}", output.rows, output.cols);
+        Ok(output)
+    }
+
+    // Reverse of forward. Each residual sum x + f(x) sends its gradient
+    // both through f and straight to x, so the two are added at the split.
+    fn backward(&mut self, gradients: &Matrix<T>, ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("TransformerBlock backward pass");
+        let d_residual = self.norm2.backward(gradients, ws)?;
+        let mut d_normed_attention_output = self.feed_forward.backward(&d_residual, ws)?;
+        d_normed_attention_output.try_add_assign(&d_residual)?;
+        ws.give(d_residual);
+        let d_residual = self.norm1.backward(&d_normed_attention_output, ws)?;
+        ws.give(d_normed_attention_output);
+        let mut d_input = self.attention.backward(&d_residual, ws)?;
+        d_input.try_add_assign(&d_residual)?;
+        ws.give(d_residual);
+        Ok(d_input)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)> {
+        let mut params = nested("attention", self.attention.named_parameters());
+        params.extend(nested("norm1", self.norm1.named_parameters()));
+        params.extend(nested("feed_forward", self.feed_forward.named_parameters()));
+        params.extend(nested("norm2", self.norm2.named_parameters()));
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)> {
+        let mut params = nested("attention", self.attention.named_parameters_mut());
+        params.extend(nested("norm1", self.norm1.named_parameters_mut()));
+        params.extend(nested("feed_forward", self.feed_forward.named_parameters_mut()));
+        params.extend(nested("norm2", self.norm2.named_parameters_mut()));
+        params
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.attention.set_training(training);
+        self.norm1.set_training(training);
+        self.feed_forward.set_training(training);
+        self.norm2.set_training(training);
+    }
+
+    fn is_training(&self) -> bool {
+        self.attention.is_training()
+    }
+}
+
+struct Transformer<T> {
+    embedding: Embedding<T>,
+    blocks: Vec<TransformerBlock<T>>,
+    output_layer: FeedForward<T>,
+    // Shared so a Module call can borrow the pool alongside the model
+    workspace: Arc<Workspace<T>>,
+    // Stream that next-token sampling draws from
+    sampler: Rng,
+}
+
+impl<T: Float> Transformer<T> {
+    fn new(vocab_size: usize, embedding_dim: usize, num_blocks: usize, heads: usize, init: InitConfig, seed: u64) -> Self {
+        println!("Creating Transformer: vocab_size={}, embedding_dim={}, num_blocks={}, heads={}, precision={}", vocab_size, embedding_dim, num_blocks, heads, T::NAME);
+        println!("Weight initialization: {:?}", init);
+        let mut rng = Rng::stream(seed, "init");
+        let mut embedding = Embedding::new(vocab_size, embedding_dim);
+        initialize_weights(&mut embedding.embeddings.value, init.embedding, &mut rng);
+
+        let residual_scale = if init.scale_residual { 1.0 / (2.0 * num_blocks as f64).sqrt() } else { 1.0 };
+        let mut blocks = Vec::new();
+        for i in 0..num_blocks {
+            println!("Initializing TransformerBlock {}", i);
+            let mut block = TransformerBlock::new(heads, embedding_dim);
+            initialize_weights(&mut block.attention.w_q.value, init.linear, &mut rng);
+            initialize_weights(&mut block.attention.w_k.value, init.linear, &mut rng);
+            initialize_weights(&mut block.attention.w_v.value, init.linear, &mut rng);
+            initialize_weights(&mut block.attention.w_o.value, init.linear, &mut rng);
+            initialize_weights(&mut block.feed_forward.w1.value, init.relu, &mut rng);
+            initialize_weights(&mut block.feed_forward.w2.value, init.linear, &mut rng);
+            if residual_scale != 1.0 {
+                block.attention.w_o.value.scale_inplace(T::from_f64(residual_scale));
+                block.feed_forward.w2.value.scale_inplace(T::from_f64(residual_scale));
+            }
+            blocks.push(block);
+        }
+
+        let mut output_layer = FeedForward::new(embedding_dim, vocab_size);
+        initialize_weights(&mut output_layer.w1.value, init.relu, &mut rng);
+        initialize_weights(&mut output_layer.w2.value, init.linear, &mut rng);
+
+        Transformer {
+            embedding,
+            blocks,
+            output_layer,
+            workspace: Arc::new(Workspace::new()),
+            sampler: Rng::stream(seed, "sampling"),
+        }
+    }
+
+    // Preallocate the scratch buffers a forward pass over seq_len tokens
+    // needs; longer sequences grow the pool on demand
+    fn reserve_workspace(&self, seq_len: usize) {
+        let dim = self.embedding.embedding_dim;
+        let mut shapes = vec![(seq_len, dim); 6];
+        if let Some(block) = self.blocks.first() {
+            // q, k, v and the context laid out by head, and the attention probabilities
+            shapes.extend([(seq_len, dim); 4]);
+            shapes.push((block.attention.heads * seq_len, seq_len));
+        }
+        shapes.push((seq_len, dim * 4));
+        shapes.push((seq_len, self.output_layer.output_dim));
+        self.workspace.reserve(&shapes);
+    }
+
+    // Logits from Module::forward in inference mode, which keeps no state for
+    // backward. The model's mode is restored afterwards.
+    fn infer(&mut self, input: &[usize]) -> Result<Matrix<T>, TensorError> {
+        let training = self.is_training();
+        self.set_training(false);
+        let ws = Arc::clone(&self.workspace);
+        let output = self.forward(input, &ws);
+        self.set_training(training);
+        output
+    }
+
+    // The forward pass recorded on a tape, returning the logits
+    #[cfg(test)]
+    fn forward_tape(&self, tape: &mut Tape<T>, input: &[usize]) -> Result<Var, TensorError> {
+        if input.is_empty() {
+            return Err(TensorError::OutOfBounds { index: vec![0], shape: vec![0] });
+        }
+        let embedded = self.embedding.forward_tape(tape, input)?;
+        let mut encoding = tape.ws.take(input.len(), self.embedding.embedding_dim);
+        positional_encoding(&mut encoding);
+        let encoding = tape.leaf(encoding);
+        let mut x = tape.add(embedded, encoding)?;
+        for block in &self.blocks {
+            x = block.forward_tape(tape, x)?;
+        }
+        self.output_layer.forward_tape(tape, x)
+    }
+
+    // Forward and backward for one sequence, adding its gradients to every
+    // parameter's accumulated gradient. Returns the summed cross-entropy.
+    fn accumulate_gradients(&mut self, input: &[usize], target: &[usize]) -> Result<f64, TensorError> {
+        println!("Accumulating gradients for input of length {}", input.len());
+        if target.len() != input.len() {
+            return Err(TensorError::ShapeMismatch { op: "training targets", left: vec![input.len()], right: vec![target.len()] });
+        }
+        let ws = Arc::clone(&self.workspace);
+        self.set_training(true);
+        let output = self.forward(input, &ws)?;
+        // Hand the logits back before failing, so a skipped batch leaks no buffers
+        let checked = match target.iter().find(|&&target_index| target_index >= output.cols) {
+            Some(&target_index) => Err(TensorError::OutOfBounds { index: vec![target_index], shape: vec![output.cols] }),
+            None => output.check_finite("logits"),
+        };
+        if let Err(error) = checked {
+            ws.give(output);
+            return Err(error);
+        }
+        let mut loss = 0.0;
+
+        // Gradient of the summed cross-entropy with respect to the logits
+        let mut gradients = ws.take(output.rows, output.cols);
+        for (i, &target_index) in target.iter().enumerate() {
+            let probs = softmax(output.row(i));
+            for (j, &prob) in probs.iter().enumerate() {
+                gradients.set(i, j, prob - if j == target_index { T::one() } else { T::zero() });
+            }
+            loss -= (probs[target_index].to_f64() + 1e-10).ln();
+        }
+        println!("Calculated loss: {}", loss);
+
+        // Backpropagate from the logits through the cached forward pass
+        println!("Backpropagating through the model");
+        ws.give(output);
+        let result = self.backward(&gradients, &ws);
+        ws.give(gradients);
+        result.map(|()| loss)
+    }
+
+    // One optimizer step over a batch of (input, target) sequences. Gradients
+    // accumulate one sequence at a time, so memory does not grow with the
+    // batch, and are averaged and clipped before the single update. A
+    // non-finite gradient fails the batch before any weight changes. Returns
+    // the mean loss per sequence.
+    fn train(&mut self, batch: &[(&[usize], &[usize])], optimizer: &mut dyn Optimizer<T>, clipping: &GradientClipping) -> Result<f64, TensorError> {
+        println!("Training on a batch of {} sequences", batch.len());
+        if batch.is_empty() {
+            return Err(TensorError::OutOfBounds { index: vec![0], shape: vec![0] });
+        }
+        let allocations_at_start = matrix_allocations();
+        self.zero_grad();
+        let mut loss = 0.0;
+        for &(input, target) in batch {
+            loss += self.accumulate_gradients(input, target)?;
+        }
+        loss /= batch.len() as f64;
+
+        // Average the gradients so the step size does not grow with the batch
+        let mut params = self.named_parameters_mut();
+        let scale = T::from_f64(1.0 / batch.len() as f64);
+        for (_, param) in params.iter_mut() {
+            param.grad.scale_inplace(scale);
+        }
+        clipping.apply(&mut params)?;
+        println!("Updating parameters with {}, learning rate {}", optimizer.name(), optimizer.learning_rate());
+        optimizer.step(params)?;
+
+        println!("Batch loss: {}", loss);
+        println!("Matrix allocations this step: {}", matrix_allocations() - allocations_at_start);
+        self.workspace.report();
+
+        Ok(loss)
+    }
+
+    // Print what the model generates and predicts after one training sequence
+    fn show_predictions(&mut self, input: &[usize], target: &[usize], tokenizer: &dyn Tokenizer, temperature: f64) -> Result<(), TensorError> {
+        let input_text = tokenizer.decode(input);
+
+        let generated_sequence = self.generate_sequence(&input_text, tokenizer, temperature)?;
+
+        println!("Input: '{}...{}'", 
+            input_text.chars().take(20).collect::<String>(),
+            input_text.chars().rev().take(40).collect::<String>().chars().rev().collect::<String>()
+        );
+        println!("Predicted next tokens (multiple words): '{}'", generated_sequence);
+        println!("Actual next token: '{}'", tokenizer.id_to_token(target[target.len() - 1]));
+
+        let prediction = self.predict_next_token(input, tokenizer, temperature)?;
+        println!("Predicted next token: '{}'", tokenizer.id_to_token(prediction));
+        Ok(())
+    }
+
+    fn predict_next_token(&mut self, input: &[usize], tokenizer: &dyn Tokenizer, temperature: f64) -> Result<usize, TensorError> {
+        let output = self.infer(input)?;
+        let mut logits = output.row(output.rows - 1).to_vec();
+        self.workspace.give(output);
+        
+        // Apply temperature
+        logits.iter_mut().for_each(|logit| *logit /= T::from_f64(temperature));
+        
+        let mut probs: Vec<f64> = softmax(&logits).into_iter().map(T::to_f64).collect();
+        
+        // Never sample the unknown token
+        if let Some(unk_index) = tokenizer.unknown_id() {
+            probs[unk_index] = 0.0;
+        }
+        
+        Ok(self.sampler.categorical(&probs))
+    }
+
+    fn generate_sequence(&mut self, prompt: &str, tokenizer: &dyn Tokenizer, temperature: f64) -> Result<String, TensorError> {
+        let mut input_tokens = tokenizer.encode(prompt);
+        let mut generated_words = Vec::with_capacity(10);
+        println!("Generating sequence from prompt: '{}'", prompt);
+
+        let mut generated_ids = Vec::with_capacity(10);
+        for i in 0..10 {
+            let output = self.infer(&input_tokens)?;
+            let last_row: Vec<T> = output.row(output.rows - 1).iter().map(|&logit| logit / T::from_f64(temperature)).collect();
+            self.workspace.give(output);
+            
+            let mut probs: Vec<f64> = softmax(&last_row).into_iter().map(T::to_f64).collect();
+            if let Some(unk_index) = tokenizer.unknown_id() {
+                probs[unk_index] = 0.0;
+            }
+            let next_token = self.sampler.categorical(&probs);
+
+            let next_word = tokenizer.id_to_token(next_token);
+            println!("Generated token {}: '{}'", i + 1, next_word);
+            generated_words.push(next_word);
+            generated_ids.push(next_token);
+
+            input_tokens.push(next_token);
+            if input_tokens.len() > self.embedding.embeddings.value.rows {
+                input_tokens.remove(0);
+            }
+        }
+
+        let generated_sequence = tokenizer.decode(&generated_ids);
+        println!("Complete generated sequence: '{}'", generated_sequence);
+        
+        // Print all tokens at once
+        println!("All generated tokens: {:?}", generated_words);
+
+        Ok(generated_sequence)
+    }
+}
+
+impl<T: Float> Module<T> for Transformer<T> {
+    type Input = [usize];
+    type InputGrad = ();
+
+    // Logits for every position of input
+    fn forward(&mut self, input: &[usize], ws: &Workspace<T>) -> Result<Matrix<T>, TensorError> {
+        println!("Transformer forward pass");
+        if input.is_empty() {
+            return Err(TensorError::OutOfBounds { index: vec![0], shape: vec![0] });
+        }
+        let mut x = self.embedding.forward(input, ws)?;
+        println!("Embedded input shape: {}x{}", x.rows, x.cols);
+        let mut encoding = ws.take(x.rows, x.cols);
+        positional_encoding(&mut encoding);
+        x.try_add_assign(&encoding)?;
+        ws.give(encoding);
+        println!("After positional encoding: {}x{}", x.rows, x.cols);
+
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            println!("Processing TransformerBlock {}", i);
+            let next = block.forward(&x, ws)?;
+            ws.give(std::mem::replace(&mut x, next));
+            println!("After block {}: {}x{}", i, x.rows, x.cols);
+        }
+
+        println!("Applying output layer");
+        let output = self.output_layer.forward(&x, ws)?;
+        ws.give(x);
+        println!("Final output shape: {}x{}", output.rows, output.cols);
+        Ok(output)
+    }
+
+    fn backward(&mut self, gradients: &Matrix<T>, ws: &Workspace<T>) -> Result<(), TensorError> {
+        println!("Transformer backward pass");
+        let mut d_x = self.output_layer.backward(gradients, ws)?;
+        for block in self.blocks.iter_mut().rev() {
+            let next = block.backward(&d_x, ws)?;
+            ws.give(std::mem::replace(&mut d_x, next));
+        }
+        // The positional encoding is constant, so d_x flows straight to the embeddings
+        let result = self.embedding.backward(&d_x, ws);
+        ws.give(d_x);
+        result
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Param<T>)> {
+        let mut params = nested("embedding", self.embedding.named_parameters());
+        for (i, block) in self.blocks.iter().enumerate() {
+            params.extend(nested(&format!("blocks.{}", i), block.named_parameters()));
+        }
+        params.extend(nested("output_layer", self.output_layer.named_parameters()));
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Param<T>)> {
+        let mut params = nested("embedding", self.embedding.named_parameters_mut());
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            params.extend(nested(&format!("blocks.{}", i), block.named_parameters_mut()));
+        }
+        params.extend(nested("output_layer", self.output_layer.named_parameters_mut()));
+        params
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.embedding.set_training(training);
+        for block in &mut self.blocks {
+            block.set_training(training);
+        }
+        self.output_layer.set_training(training);
+    }
+
+    fn is_training(&self) -> bool {
+        self.embedding.is_training()
+    }
+}
+
+// Limits applied to the averaged gradients before each optimizer step
+struct GradientClipping {
+    // Rescale all gradients together when their global L2 norm exceeds this
+    max_norm: Option<f64>,
+    // Clamp every gradient entry to [-max_value, max_value]
+    max_value: Option<f64>,
+}
+
+impl GradientClipping {
+    // Fails without touching anything if a gradient holds NaN or Inf, naming
+    // the parameter. Otherwise clips and returns the global norm from before
+    // norm clipping.
+    fn apply<T: Float>(&self, params: &mut [(String, &mut Param<T>)]) -> Result<f64, TensorError> {
+        for (name, param) in params.iter() {
+            if let Some(i) = param.grad.data.iter().position(|g| !g.to_f64().is_finite()) {
+                return Err(TensorError::NonFiniteGradient { param: name.clone(), index: vec![i / param.grad.cols, i % param.grad.cols] });
+            }
+        }
+        if let Some(max_value) = self.max_value {
+            let (low, high) = (T::from_f64(-max_value), T::from_f64(max_value));
+            for (_, param) in params.iter_mut() {
+                param.grad.data.iter_mut().for_each(|g| *g = if *g > high { high } else { g.max(low) });
+            }
+        }
+        let norm = params.iter().map(|(_, param)| param.grad.data.iter().map(|g| g.to_f64() * g.to_f64()).sum::<f64>()).sum::<f64>().sqrt();
+        match self.max_norm {
+            Some(max_norm) if norm > max_norm => {
+                println!("Gradient norm {} clipped to {}", norm, max_norm);
+                let scale = T::from_f64(max_norm / norm);
+                for (_, param) in params.iter_mut() {
+                    param.grad.scale_inplace(scale);
+                }
+            }
+            _ => println!("Gradient norm {}", norm),
+        }
+        Ok(norm)
+    }
+}
+
+// A set of parameters sharing optimizer hyperparameters. Each parameter
+// joins the first group whose filter accepts its name.
+struct ParamGroup {
+    name: &'static str,
+    weight_decay: f64,
+    filter: fn(&str) -> bool,
+}
+
+// LayerNorm gamma/beta and the feed-forward biases (b1, b2)
+fn is_norm_or_bias(name: &str) -> bool {
+    let last = name.rsplit('.').next().unwrap_or(name);
+    let bias = last.len() > 1 && last.starts_with('b') && last[1..].bytes().all(|c| c.is_ascii_digit());
+    bias || last == "gamma" || last == "beta"
+}
+
+// Weight decay for weight matrices and embeddings, none for norms and biases
+fn default_param_groups(weight_decay: f64) -> Vec<ParamGroup> {
+    vec![
+        ParamGroup { name: "no_decay", weight_decay: 0.0, filter: is_norm_or_bias },
+        ParamGroup { name: "decay", weight_decay, filter: |_| true },
+    ]
+}
+
+// Per-parameter state kept in f64 whatever the parameter's element type
+struct ParamState {
+    weight_decay: f64,
+    slots: Vec<Vec<f64>>,
+}
+
+// Group assignment and state for every parameter an optimizer has seen,
+// keyed by the parameter's name
+struct OptimizerState {
+    groups: Vec<ParamGroup>,
+    params: HashMap<String, ParamState>,
+}
+
+impl OptimizerState {
+    fn new(groups: Vec<ParamGroup>) -> Self {
+        OptimizerState { groups, params: HashMap::new() }
+    }
+
+    // State for name with slot_count zeroed slots of len entries, created on
+    // first use. Parameters no group accepts get no weight decay.
+    fn get(&mut self, name: &str, len: usize, slot_count: usize) -> Result<&mut ParamState, TensorError> {
+        if !self.params.contains_key(name) {
+            let group = self.groups.iter().find(|group| (group.filter)(name));
+            println!("Optimizer state for {}: {} entries, group {}", name, len, group.map_or("none", |group| group.name));
+            let weight_decay = group.map_or(0.0, |group| group.weight_decay);
+            self.params.insert(name.to_string(), ParamState { weight_decay, slots: vec![vec![0.0; len]; slot_count] });
+        }
+        let state = self.params.get_mut(name).unwrap();
+        if state.slots.first().is_some_and(|slot| slot.len() != len) {
+            return Err(TensorError::ShapeMismatch { op: "optimizer state", left: vec![len], right: vec![state.slots[0].len()] });
+        }
+        Ok(state)
+    }
+}
+
+trait Optimizer<T: Float> {
+    fn name(&self) -> &'static str;
+
+    // Update every parameter from its accumulated gradient
+    fn step(&mut self, params: Vec<(String, &mut Param<T>)>) -> Result<(), TensorError>;
+
+    fn learning_rate(&self) -> f64;
+    fn set_learning_rate(&mut self, learning_rate: f64);
+}
+
+// SGD with optional heavy-ball or Nesterov momentum. Weight decay is L2,
+// added to the gradient.
+struct Sgd {
+    learning_rate: f64,
+    momentum: f64,
+    nesterov: bool,
+    state: OptimizerState,
+}
+
+impl Sgd {
+    fn new(learning_rate: f64, momentum: f64, nesterov: bool, groups: Vec<ParamGroup>) -> Self {
+        println!("Creating SGD optimizer: learning_rate={}, momentum={}, nesterov={}", learning_rate, momentum, nesterov);
+        Sgd { learning_rate, momentum, nesterov, state: OptimizerState::new(groups) }
+    }
+}
+
+impl<T: Float> Optimizer<T> for Sgd {
+    fn name(&self) -> &'static str {
+        if self.nesterov { "SGD (Nesterov)" } else { "SGD" }
+    }
+
+    fn step(&mut self, params: Vec<(String, &mut Param<T>)>) -> Result<(), TensorError> {
+        let slot_count = if self.momentum > 0.0 { 1 } else { 0 };
+        for (name, param) in params {
+            param.value.check_same_shape("optimizer step", &param.grad)?;
+            let state = self.state.get(&name, param.value.data.len(), slot_count)?;
+            for (i, (w, &g)) in param.value.data.iter_mut().zip(&param.grad.data).enumerate() {
+                let mut update = g.to_f64() + state.weight_decay * w.to_f64();
+                if let Some(velocity) = state.slots.first_mut() {
+                    velocity[i] = self.momentum * velocity[i] + update;
+                    update = if self.nesterov { update + self.momentum * velocity[i] } else { velocity[i] };
+                }
+                *w = T::from_f64(w.to_f64() - self.learning_rate * update);
+            }
+        }
+        Ok(())
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+}
+
+// Adam with bias-corrected moments. Weight decay is either L2 folded into
+// the gradient (Adam) or decoupled and applied to the weights (AdamW).
+struct Adam {
+    learning_rate: f64,
+    beta1: f64,
+    beta2: f64,
+    eps: f64,
+    decoupled_weight_decay: bool,
+    steps: i32,
+    state: OptimizerState,
+}
+
+impl Adam {
+    fn new(learning_rate: f64, (beta1, beta2): (f64, f64), eps: f64, decoupled_weight_decay: bool, groups: Vec<ParamGroup>) -> Self {
+        println!("Creating {} optimizer: learning_rate={}, betas=({}, {}), eps={}", if decoupled_weight_decay { "AdamW" } else { "Adam" }, learning_rate, beta1, beta2, eps);
+        Adam { learning_rate, beta1, beta2, eps, decoupled_weight_decay, steps: 0, state: OptimizerState::new(groups) }
+    }
+}
+
+impl<T: Float> Optimizer<T> for Adam {
+    fn name(&self) -> &'static str {
+        if self.decoupled_weight_decay { "AdamW" } else { "Adam" }
+    }
+
+    fn step(&mut self, params: Vec<(String, &mut Param<T>)>) -> Result<(), TensorError> {
+        self.steps += 1;
+        let correction1 = 1.0 - self.beta1.powi(self.steps);
+        let correction2 = 1.0 - self.beta2.powi(self.steps);
+        for (name, param) in params {
+            param.value.check_same_shape("optimizer step", &param.grad)?;
+            let state = self.state.get(&name, param.value.data.len(), 2)?;
+            let (decay, l2) = if self.decoupled_weight_decay { (state.weight_decay, 0.0) } else { (0.0, state.weight_decay) };
+            let (m, v) = state.slots.split_at_mut(1);
+            let (m, v) = (&mut m[0], &mut v[0]);
+            for (i, (w, &g)) in param.value.data.iter_mut().zip(&param.grad.data).enumerate() {
+                let weight = w.to_f64();
+                let g = g.to_f64() + l2 * weight;
+                m[i] = self.beta1 * m[i] + (1.0 - self.beta1) * g;
+                v[i] = self.beta2 * v[i] + (1.0 - self.beta2) * g * g;
+                let update = (m[i] / correction1) / ((v[i] / correction2).sqrt() + self.eps) + decay * weight;
+                *w = T::from_f64(weight - self.learning_rate * update);
+            }
+        }
+        Ok(())
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+}
+
+// The optimizer named by RUSTFORMER_OPTIMIZER: sgd, momentum, nesterov, adam
+// or adamw (the default)
+fn build_optimizer<T: Float>(name: &str) -> Box<dyn Optimizer<T>> {
+    match name {
+        "sgd" => Box::new(Sgd::new(0.01, 0.0, false, default_param_groups(0.0))),
+        "momentum" => Box::new(Sgd::new(0.01, 0.9, false, default_param_groups(0.0))),
+        "nesterov" => Box::new(Sgd::new(0.01, 0.9, true, default_param_groups(0.0))),
+        "adam" => Box::new(Adam::new(1e-3, (0.9, 0.999), 1e-8, false, default_param_groups(0.0))),
+        "adamw" => Box::new(Adam::new(1e-3, (0.9, 0.999), 1e-8, true, default_param_groups(0.01))),
+        other => panic!("Unknown optimizer '{}', expected one of sgd, momentum, nesterov, adam, adamw", other),
+    }
+}
+
+// Learning rate as a function of the optimizer step
+trait LrScheduler {
+    // Rate for the 0-based step
+    fn rate(&self, step: usize) -> f64;
+
+    // Text form that parse_scheduler reads back
+    fn spec(&self) -> String;
+}
+
+struct ConstantLr {
+    rate: f64,
+}
+
+impl LrScheduler for ConstantLr {
+    fn rate(&self, _: usize) -> f64 {
+        self.rate
+    }
+
+    fn spec(&self) -> String {
+        format!("constant({})", self.rate)
+    }
+}
+
+// Ramp linearly up to the inner schedule's first rate over steps, then hand
+// over to the inner schedule counting from zero
+struct Warmup {
+    steps: usize,
+    then: Box<dyn LrScheduler>,
+}
+
+impl LrScheduler for Warmup {
+    fn rate(&self, step: usize) -> f64 {
+        if step < self.steps {
+            self.then.rate(0) * (step + 1) as f64 / self.steps as f64
+        } else {
+            self.then.rate(step - self.steps)
+        }
+    }
+
+    fn spec(&self) -> String {
+        format!("warmup({},{})", self.steps, self.then.spec())
+    }
+}
+
+// Half a cosine from base down to floor over total_steps, then floor
+struct CosineDecay {
+    base: f64,
+    floor: f64,
+    total_steps: usize,
+}
+
+impl LrScheduler for CosineDecay {
+    fn rate(&self, step: usize) -> f64 {
+        let progress = step.min(self.total_steps) as f64 / self.total_steps as f64;
+        self.floor + (self.base - self.floor) * 0.5 * (1.0 + (std::f64::consts::PI * progress).cos())
+    }
+
+    fn spec(&self) -> String {
+        format!("cosine({},{},{})", self.base, self.floor, self.total_steps)
+    }
+}
+
+// base, multiplied by gamma every `every` steps
+struct StepDecay {
+    base: f64,
+    gamma: f64,
+    every: usize,
+}
+
+impl LrScheduler for StepDecay {
+    fn rate(&self, step: usize) -> f64 {
+        self.base * self.gamma.powi((step / self.every) as i32)
+    }
+
+    fn spec(&self) -> String {
+        format!("step({},{},{})", self.base, self.gamma, self.every)
+    }
+}
+
+// The original Transformer schedule: linear warmup to base at warmup_steps,
+// then decay with the inverse square root of the step
+struct InverseSqrt {
+    base: f64,
+    warmup_steps: usize,
+}
+
+impl LrScheduler for InverseSqrt {
+    fn rate(&self, step: usize) -> f64 {
+        let (step, warmup) = ((step + 1) as f64, self.warmup_steps as f64);
+        self.base * (step / warmup).min((warmup / step).sqrt())
+    }
+
+    fn spec(&self) -> String {
+        format!("inverse_sqrt({},{})", self.base, self.warmup_steps)
+    }
+}
+
+// Read a scheduler from its spec, e.g. warmup(100,cosine(0.001,0.0001,5000))
+fn parse_scheduler(spec: &str) -> Result<Box<dyn LrScheduler>, ParseError> {
+    let error = |reason: &str| ParseError { what: "learning rate schedule", input: spec.to_string(), reason: reason.to_string() };
+    let spec = spec.trim();
+    let (name, rest) = spec.split_once('(').ok_or_else(|| error("expected name(arguments)"))?;
+    let inner = rest.strip_suffix(')').ok_or_else(|| error("missing closing parenthesis"))?;
+
+    // Split at top-level commas so nested schedules stay whole
+    let mut args = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1).ok_or_else(|| error("unbalanced parentheses"))?,
+            ',' if depth == 0 => {
+                args.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(inner[start..].trim());
+
+    let expect = |count: usize| if args.len() == count { Ok(()) } else { Err(error(&format!("{} takes {} arguments, got {}", name, count, args.len()))) };
+    let rate = |i: usize| args[i].parse::<f64>().map_err(|_| error(&format!("'{}' is not a number", args[i])));
+    let steps = |i: usize| match args[i].parse::<usize>() {
+        Ok(steps) if steps > 0 => Ok(steps),
+        _ => Err(error(&format!("'{}' is not a positive step count", args[i]))),
+    };
+    let scheduler: Box<dyn LrScheduler> = match name.trim() {
+        "constant" => {
+            expect(1)?;
+            Box::new(ConstantLr { rate: rate(0)? })
+        }
+        "warmup" => {
+            expect(2)?;
+            Box::new(Warmup { steps: steps(0)?, then: parse_scheduler(args[1])? })
+        }
+        "cosine" => {
+            expect(3)?;
+            Box::new(CosineDecay { base: rate(0)?, floor: rate(1)?, total_steps: steps(2)? })
+        }
+        "step" => {
+            expect(3)?;
+            Box::new(StepDecay { base: rate(0)?, gamma: rate(1)?, every: steps(2)? })
+        }
+        "inverse_sqrt" => {
+            expect(2)?;
+            Box::new(InverseSqrt { base: rate(0)?, warmup_steps: steps(1)? })
+        }
+        other => return Err(error(&format!("unknown schedule '{}'", other))),
+    };
+    Ok(scheduler)
+}
+
+// A scheduler and how far along it the run is. Saved as "<spec> @ <step>" so
+// a resumed run picks up on the same curve.
+struct LrSchedule {
+    scheduler: Box<dyn LrScheduler>,
+    step: usize,
+}
+
+impl LrSchedule {
+    fn new(scheduler: Box<dyn LrScheduler>) -> Self {
+        LrSchedule { scheduler, step: 0 }
+    }
+
+    // Warm up over 5% of the run (at most 200 steps), then cosine-decay to a
+    // tenth of the base rate
+    fn default_for(base: f64, total_steps: usize) -> Self {
+        let warmup = (total_steps / 20).clamp(1, 200);
+        let decay = CosineDecay { base, floor: base * 0.1, total_steps: total_steps.saturating_sub(warmup).max(1) };
+        LrSchedule::new(Box::new(Warmup { steps: warmup, then: Box::new(decay) }))
+    }
+
+    // The rate for the current step, advancing to the next
+    fn next_rate(&mut self) -> f64 {
+        let rate = self.scheduler.rate(self.step);
+        self.step += 1;
+        rate
+    }
+
+    fn save(&self) -> String {
+        format!("{} @ {}", self.scheduler.spec(), self.step)
+    }
+
+    // A saved schedule, or a bare spec to start from step 0
+    fn load(text: &str) -> Result<Self, ParseError> {
+        let (spec, step) = match text.rsplit_once('@') {
+            Some((spec, step)) => {
+                let step = step.trim().parse().map_err(|_| ParseError { what: "learning rate schedule", input: text.to_string(), reason: format!("'{}' is not a step", step.trim()) })?;
+                (spec, step)
+            }
+            None => (text, 0),
+        };
+        Ok(LrSchedule { scheduler: parse_scheduler(spec)?, step })
+    }
+}
+
+fn main() {
+    println!("Starting main function");
+
+    // Read the text file
+    let contents = include_str!("../Heany.txt");
+    println!("Read file contents, length: {}", contents.len());
+
+    // Tokenize the text
+    // RUSTFORMER_TOKENIZER_FILE keeps the tokenizer across runs: it is loaded
+    // if the file exists, otherwise fitted and saved there
+    let tokenizer_name = std::env::var("RUSTFORMER_TOKENIZER").unwrap_or_else(|_| "bpe".to_string());
+    let tokenizer = match std::env::var("RUSTFORMER_TOKENIZER_FILE") {
+        Ok(path) if std::path::Path::new(&path).exists() => {
+            println!("Loading tokenizer from {}", path);
+            let text = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+            load_tokenizer(&text).unwrap_or_else(|e| panic!("{}", e))
+        }
+        Ok(path) => {
+            let tokenizer = build_tokenizer(&tokenizer_name, contents);
+            std::fs::write(&path, tokenizer.save()).unwrap_or_else(|e| panic!("Could not write {}: {}", path, e));
+            println!("Saved tokenizer to {}", path);
+            tokenizer
+        }
+        Err(_) => build_tokenizer(&tokenizer_name, contents),
+    };
+    let tokens = tokenizer.encode(contents);
+    println!("Tokenized text, number of tokens: {}", tokens.len());
+
+    // Size the thread pool, defaulting to one worker per core
+    let num_threads = std::env::var("RUSTFORMER_THREADS").ok().and_then(|v| v.parse().ok()).unwrap_or(0);
+    set_num_threads(num_threads);
+
+    // Choose the element type used by every layer
+    let precision = std::env::var("RUSTFORMER_PRECISION").unwrap_or_else(|_| "f64".to_string());
+    match precision.as_str() {
+        "f32" => run::<f32>(&tokens, tokenizer.as_ref()),
+        "f64" => run::<f64>(&tokens, tokenizer.as_ref()),
+        "bf16" => run::<Bf16>(&tokens, tokenizer.as_ref()),
+        "f16" => run::<F16>(&tokens, tokenizer.as_ref()),
+        other => panic!("Unknown precision '{}', expected one of f32, f64, bf16, f16", other),
+    }
+}
+
+fn run<T: Float>(tokens: &[usize], tokenizer: &dyn Tokenizer) {
+    // Define training parameters
+    let seq_length = 40;
+    let epochs = 4;
+    let optimizer_name = std::env::var("RUSTFORMER_OPTIMIZER").unwrap_or_else(|_| "adamw".to_string());
+    let mut optimizer = build_optimizer::<T>(&optimizer_name);
+
+    // Each optimizer step averages gradients over batch_size sequences
+    let batch_size = std::env::var("RUSTFORMER_BATCH_SIZE").ok().and_then(|v| v.parse().ok()).filter(|&n| n > 0).unwrap_or(32);
+
+    // Clip the global gradient norm to RUSTFORMER_CLIP_NORM (1.0 by default,
+    // 0 to disable) and, if set, each entry to RUSTFORMER_CLIP_VALUE
+    let env_limit = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<f64>().ok());
+    let clipping = GradientClipping {
+        max_norm: Some(env_limit("RUSTFORMER_CLIP_NORM").unwrap_or(1.0)).filter(|&n| n > 0.0),
+        max_value: env_limit("RUSTFORMER_CLIP_VALUE").filter(|&v| v > 0.0),
+    };
+
+    let sequences = tokens.len() - seq_length - 1;
+    let total_iterations = epochs * sequences;
+    let total_steps = epochs * sequences.div_ceil(batch_size);
+    let mut current_iteration = 0;
+
+    // RUSTFORMER_SCHEDULE takes a spec or a saved "<spec> @ <step>" to resume
+    let mut schedule = match std::env::var("RUSTFORMER_SCHEDULE") {
+        Ok(text) => LrSchedule::load(&text).unwrap_or_else(|e| panic!("{}", e)),
+        Err(_) => LrSchedule::default_for(optimizer.learning_rate(), total_steps),
+    };
+    println!("Learning rate schedule: {}", schedule.save());
+
+    // Initialize the transformer
+    let vocab_size = tokenizer.vocab_size();
+    let embedding_dim = 128;
+    let num_blocks = 3;
+    let heads = 4;
+
+    println!("Initializing transformer with vocab_size={}, embedding_dim={}, num_blocks={}, heads={}", 
+             vocab_size, embedding_dim, num_blocks, heads);
+    // RUSTFORMER_INIT picks the weight initialization: gpt2 (the default),
+    // xavier, kaiming or uniform
+    let init_name = std::env::var("RUSTFORMER_INIT").unwrap_or_else(|_| "gpt2".to_string());
+    let init = InitConfig::preset(&init_name)
+        .unwrap_or_else(|| panic!("Unknown initialization '{}', expected one of gpt2, xavier, kaiming, uniform", init_name));
+    // Weight init and sampling draw from their own streams of RUSTFORMER_SEED
+    let seed = std::env::var("RUSTFORMER_SEED").ok().and_then(|v| v.parse().ok()).unwrap_or(12242);
+    let mut transformer = Transformer::<T>::new(vocab_size, embedding_dim, num_blocks, heads, init, seed);
+    println!("Transformer has {} parameters", transformer.parameter_count());
+    transformer.reserve_workspace(seq_length);
+
+    let temperature = 0.8;
+
+    println!("Starting training loop: seq_length={}, epochs={}, batch_size={}, optimizer={}, tokenizer={}", seq_length, epochs, batch_size, optimizer.name(), tokenizer.name());
+    for epoch in 0..epochs {
+        println!("Starting epoch {}", epoch + 1);
+        let mut total_loss = 0.0;
+        let mut batch_count = 0;
+        for i in (0..sequences).step_by(batch_size) {
+            let batch: Vec<(&[usize], &[usize])> = (i..(i + batch_size).min(sequences))
+                .map(|j| (&tokens[j..j + seq_length], &tokens[j + 1..j + seq_length + 1]))
+                .collect();
+
+            optimizer.set_learning_rate(schedule.next_rate());
+            match transformer.train(&batch, optimizer.as_mut(), &clipping) {
+                Ok(loss) => {
+                    total_loss += loss;
+                    batch_count += 1;
+                    println!("Epoch {}, Batch {}: Average Loss = {}", epoch + 1, batch_count, loss);
+                }
+                Err(e) => println!("Skipping batch at token {}: {}", i, e),
+            }
+
+            current_iteration += batch.len();
+            let progress = (current_iteration as f64 / total_iterations as f64) * 100.0;
+            println!("Progress: {:.2}% ({}/{})", progress, current_iteration, total_iterations);
+        }
+        println!("Epoch {} completed, Average Loss: {}", epoch + 1, total_loss / batch_count as f64);
+        println!("Learning rate schedule state: {}", schedule.save());
+
+        // Sample from the epoch's last sequence, apart from any batch's update
+        let last = sequences - 1;
+        if let Err(e) = transformer.show_predictions(&tokens[last..last + seq_length], &tokens[last + 1..last + seq_length + 1], tokenizer, temperature) {
+            println!("Sampling failed: {}", e);
+        }
+    }
+
+
+    // Generate predictions
+    let prompt = "Between my finger and my thumb";
+    println!("Generating predictions for prompt: '{}'", prompt);
+
+    let temperature = 0.8;
+    match transformer.generate_sequence(prompt, tokenizer, temperature) {
+        Ok(generated_sequence) => println!("Generated sequence: {}", generated_sequence),
+        Err(e) => println!("Generation failed: {}", e),
+    }
+    transformer.workspace.report();
+    println!("Prediction generation completed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_precision_reductions_accumulate_in_f32() {
+        fn check<T: Float>() {
+            let ones = vec![T::one(); 512];
+            let alternating: Vec<T> = (0..512).map(|i| T::from_f64((i % 2 * 2) as f64)).collect();
+            assert_eq!(T::reduce_sum(&ones).to_f64(), 512.0, "{} sum", T::NAME);
+            assert_eq!(T::dot(&ones, &ones).to_f64(), 512.0, "{} dot", T::NAME);
+            assert_eq!(T::sum_sq_dev(&alternating, T::one()).to_f64(), 512.0, "{} sum of squared deviations", T::NAME);
+        }
+        check::<Bf16>();
+        check::<F16>();
+    }
+
+    // Every SIMD level this CPU supports against the scalar kernels
+    #[test]
+    fn simd_kernels_match_scalar_reference() {
+        let mut rng = Rng::new(4242);
+        for level in SimdLevel::available() {
+            let err_f32 = simd_f32::check(level, &mut rng);
+            assert!(err_f32 < 1e-5, "{:?} f32 kernels: max relative error {:e}", level, err_f32);
+            let err_f64 = simd_f64::check(level, &mut rng);
+            assert!(err_f64 < 1e-12, "{:?} f64 kernels: max relative error {:e}", level, err_f64);
+        }
+    }
+
+    // Every gemm layout and the in-place ops against a naive triple loop,
+    // at a size large enough to take the threaded path
+    #[test]
+    fn gemm_layouts_match_naive_matmul() {
+        let (m, n, k) = (70, 90, 33);
+        let mut rng = Rng::new(7171);
+        let mut random = |rows, cols| {
+            let mut matrix = Matrix::<f64>::new(rows, cols);
+            matrix.data.iter_mut().for_each(|x| *x = rng.uniform(-1.0, 1.0));
+            matrix
+        };
+        let (a, b, c) = (random(m, k), random(k, n), random(m, n));
+        let mut expected = c.clone();
+        for i in 0..m {
+            for j in 0..n {
+                let sum: f64 = (0..k).map(|p| a.get(i, p) * b.get(p, j)).sum();
+                expected.set(i, j, 2.0 * (expected.get(i, j) + sum) + 0.5 * c.get(i, j));
+            }
+        }
+
+        // Same operands stored transposed for the NT and TN layouts
+        let transposed = |matrix: &Matrix<f64>| {
+            let mut result = Matrix::new(matrix.cols, matrix.rows);
+            for i in 0..matrix.rows {
+                for j in 0..matrix.cols {
+                    result.set(j, i, matrix.get(i, j));
+                }
+            }
+            result
+        };
+        let (a_t, b_t) = (transposed(&a), transposed(&b));
+        for (op, left, right) in [(GemmOp::NN, &a, &b), (GemmOp::NT, &a, &b_t), (GemmOp::TN, &a_t, &b)] {
+            let mut got = c.clone();
+            got.try_gemm(op, left, right).unwrap();
+            got.scale_inplace(2.0);
+            got.try_axpy(0.5, &c).unwrap();
+            let max_err = got.data.iter().zip(&expected.data).fold(0.0f64, |acc, (g, e)| acc.max((g - e).abs()));
+            assert!(max_err < 1e-12, "{:?}: max abs error {:e}", op, max_err);
+        }
+    }
+
+    #[test]
+    fn gemm_rejects_shape_mismatch() {
+        let (a, b) = (Matrix::<f64>::new(70, 33), Matrix::<f64>::new(33, 90));
+        assert!(Matrix::<f64>::new(70, 90).try_gemm(GemmOp::TN, &a, &b).is_err());
+    }
+
+    #[test]
+    fn tensor_ops_broadcast() {
+        let a = Tensor::from_vec((0..6).map(|x| x as f64).collect(), &[2, 3]).unwrap();
+        let row = Tensor::from_vec(vec![10.0, 20.0, 30.0], &[3]).unwrap();
+        let col = Tensor::from_vec(vec![1.0, 2.0], &[2, 1]).unwrap();
+        assert_eq!(a.view().add(&row.view()).unwrap().data, vec![10.0, 21.0, 32.0, 13.0, 24.0, 35.0]);
+        assert_eq!(a.view().mul(&col.view()).unwrap().data, vec![0.0, 1.0, 2.0, 6.0, 8.0, 10.0]);
+        assert_eq!(a.view().sub(&row.view()).unwrap().view().div(&col.view()).unwrap().data, vec![-10.0, -19.0, -28.0, -3.5, -8.0, -12.5]);
+    }
+
+    #[test]
+    fn tensor_views_share_storage() {
+        let a = Tensor::from_vec((0..6).map(|x| x as f64).collect(), &[2, 3]).unwrap();
+        let transposed = a.view().permute(&[1, 0]).unwrap();
+        assert_eq!(transposed.get(&[2, 1]), Ok(5.0));
+        assert_eq!(transposed.slice(0, 1, 3).unwrap().to_tensor().data, vec![1.0, 4.0, 2.0, 5.0]);
+        assert_eq!(a.view().reshape(&[3, 2]).unwrap().get(&[2, 0]), Ok(4.0));
+    }
+
+    #[test]
+    fn tensor_ops_reject_bad_shapes() {
+        let a = Tensor::from_vec((0..6).map(|x| x as f64).collect(), &[2, 3]).unwrap();
+        let transposed = a.view().permute(&[1, 0]).unwrap();
+        assert!(Tensor::from_vec(vec![1.0; 5], &[2, 3]).is_err());
+        assert!(matches!(a.view().get(&[2, 0]), Err(TensorError::OutOfBounds { .. })));
+        assert!(matches!(a.view().reshape(&[4, 2]), Err(TensorError::ShapeMismatch { .. })));
+        assert!(matches!(transposed.reshape(&[6]), Err(TensorError::NonContiguous { .. })));
+        assert!(matches!(a.view().permute(&[0, 0]), Err(TensorError::InvalidAxes { .. })));
+        assert!(matches!(a.view().permute(&[0]), Err(TensorError::InvalidAxes { .. })));
+        assert!(matches!(a.view().slice(1, 2, 4), Err(TensorError::InvalidSlice { .. })));
+        assert!(matches!(a.view().slice(2, 0, 1), Err(TensorError::InvalidSlice { .. })));
+        assert!(matches!(a.view().broadcast_to(&[2, 4]), Err(TensorError::ShapeMismatch { .. })));
+        assert!(matches!(a.view().add(&transposed), Err(TensorError::ShapeMismatch { .. })));
+        assert!(matches!(a.view().matmul(&a.view()), Err(TensorError::ShapeMismatch { .. })));
+        assert!(matches!(a.view().matmul(&Tensor::from_vec(vec![1.0; 3], &[3]).unwrap().view()), Err(TensorError::ShapeMismatch { .. })));
+        assert!(matches!(a.view().matmul_into(&transposed, &mut [0.0; 3]), Err(TensorError::ShapeMismatch { .. })));
+    }
+
+    // Batched matmul of a view against the same product summed entry by entry
+    fn assert_matmul_matches_naive(case: &str, a: &TensorView<f64>, b: &TensorView<f64>) {
+        let got = a.matmul(b).unwrap();
+        let (batch_shape, m, k, n) = a.matmul_dims(b).unwrap();
+        let with_matrix = |dims: [usize; 2]| batch_shape.iter().copied().chain(dims).collect::<Vec<usize>>();
+        let (a, b) = (a.broadcast_to(&with_matrix([m, k])).unwrap(), b.broadcast_to(&with_matrix([k, n])).unwrap());
+        let mut max_err = 0.0f64;
+        for (flat, &value) in got.data.iter().enumerate() {
+            // Unravel the flat index of the result into batch entry, i and j
+            let (batch, i, j) = (flat / (m * n), flat / n % m, flat % n);
+            let mut batch_index = vec![0; batch_shape.len()];
+            let mut rest = batch;
+            for (index, &dim) in batch_index.iter_mut().zip(&batch_shape).rev() {
+                *index = rest % dim;
+                rest /= dim;
+            }
+            let at = |row: usize, col: usize| batch_index.iter().copied().chain([row, col]).collect::<Vec<usize>>();
+            let expected: f64 = (0..k).map(|p| a.get(&at(i, p)).unwrap() * b.get(&at(p, j)).unwrap()).sum();
+            max_err = max_err.max((value - expected).abs());
+        }
+        assert!(max_err < 1e-12, "{}: max abs error {:e}", case, max_err);
+    }
+
+    // Permuted operands are read in place by the NT and TN kernels, and other
+    // strided operands are copied first
+    #[test]
+    fn tensor_matmul_handles_strided_and_broadcast_operands() {
+        let mut rng = Rng::new(6161);
+        let mut random = |shape: &[usize]| {
+            let mut tensor = Tensor::zeros(shape);
+            tensor.data.iter_mut().for_each(|x| *x = rng.uniform(-1.0, 1.0));
+            tensor
+        };
+        let (a, a_t, b, b_t, wide) = (random(&[2, 3, 4]), random(&[2, 4, 3]), random(&[2, 4, 5]), random(&[2, 5, 4]), random(&[2, 3, 8]));
+        let (weights, batched_weights) = (random(&[4, 5]), random(&[3, 1, 4, 5]));
+        fn transposed(t: &Tensor<f64>) -> TensorView<'_, f64> {
+            t.view().permute(&[0, 2, 1]).unwrap()
+        }
+        assert_eq!(transposed(&a_t).matrix_layout(), Some(true));
+        assert_eq!(wide.view().slice(2, 2, 6).unwrap().matrix_layout(), None);
+        assert_matmul_matches_naive("NN", &a.view(), &b.view());
+        assert_matmul_matches_naive("NT", &a.view(), &transposed(&b_t));
+        assert_matmul_matches_naive("TN", &transposed(&a_t), &b.view());
+        assert_matmul_matches_naive("TT", &transposed(&a_t), &transposed(&b_t));
+        assert_matmul_matches_naive("strided", &wide.view().slice(2, 2, 6).unwrap(), &b.view());
+        assert_matmul_matches_naive("broadcast 2-D", &a.view(), &weights.view());
+        assert_matmul_matches_naive("broadcast batch", &a.view(), &batched_weights.view());
+    }
+
+    fn random_attention(rng: &mut Rng) -> MultiHeadAttention<f64> {
+        let mut attention = MultiHeadAttention::<f64>::new(2, 8);
+        for weights in attention.parameters_mut() {
+            initialize_weights(weights, Init::Uniform(1.0), rng);
+        }
+        attention
+    }
+
+    // Batched attention against the 2-D forward and backward passes run on
+    // each sequence in turn
+    #[test]
+    fn batched_attention_matches_per_sequence() {
+        let (batch, seq_len, dim) = (3, 5, 8);
+        let mut rng = Rng::new(5151);
+        let mut attention = random_attention(&mut rng);
+        let mut input = Tensor::zeros(&[batch, seq_len, dim]);
+        input.data.iter_mut().for_each(|x| *x = rng.uniform(-1.0, 1.0));
+        let mut gradients = Matrix::new(batch * seq_len, dim);
+        initialize_weights(&mut gradients, Init::Uniform(1.0), &mut rng);
+        let max_err = |got: &[f64], expected: &[f64]| got.iter().zip(expected).fold(0.0f64, |acc, (g, e)| acc.max((g - e).abs()));
+
+        let ws = Workspace::new();
+        let batched = attention.forward_batched(&input.view(), &ws).unwrap();
+        let batched_input_grad = attention.backward(&gradients, &ws).unwrap();
+        let batched_grads: Vec<f64> = attention.named_parameters().into_iter().flat_map(|(_, param)| param.grad.data.clone()).collect();
+
+        attention.zero_grad();
+        for b in 0..batch {
+            let sequence = input.view().slice(0, b, b + 1).unwrap().to_tensor().into_matrix();
+            let rows = b * seq_len * dim..(b + 1) * seq_len * dim;
+            let expected = attention.forward(&sequence, &ws).unwrap();
+            assert!(max_err(&batched.data[rows.clone()], &expected.data) < 1e-9, "sequence {} output", b);
+            let sequence_gradients = Tensor::from_vec(gradients.data[rows.clone()].to_vec(), &[seq_len, dim]).unwrap().into_matrix();
+            let input_grad = attention.backward(&sequence_gradients, &ws).unwrap();
+            assert!(max_err(&batched_input_grad.data[rows], &input_grad.data) < 1e-9, "sequence {} input gradient", b);
+        }
+        let summed: Vec<f64> = attention.named_parameters().into_iter().flat_map(|(_, param)| param.grad.data.clone()).collect();
+        assert!(max_err(&batched_grads, &summed) < 1e-9, "batched weight gradients differ from the per-sequence sum");
+    }
+
+    // A column past the end would otherwise land on the next row's storage
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn matrix_get_rejects_column_past_end() {
+        Matrix::<f64>::new(2, 3).get(0, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn matrix_set_rejects_column_past_end() {
+        Matrix::<f64>::new(2, 3).set(0, 3, 1.0);
+    }
+
+    // Fixed random projection that the gradient checks contract outputs with, so
+    // the checked loss sum(output * projection) depends on every output entry
+    fn check_projection(rows: usize, cols: usize) -> Matrix<f64> {
+        let mut projection = Matrix::new(rows, cols);
+        initialize_weights(&mut projection, Init::Uniform(1.0), &mut Rng::new(9191));
+        projection
+    }
+
+    // Gradients for each checked tensor, None where the loss does not depend on it
+    type AnalyticGradients = Vec<Option<Matrix<f64>>>;
+
+    // Gradients of the checked loss from the tape: inputs first, then parameters
+    // in registration order
+    fn tape_gradients<M>(
+        model: &M,
+        inputs: &[Matrix<f64>],
+        forward: impl Fn(&M, &mut Tape<f64>, &[Var]) -> Result<Var, TensorError>,
+    ) -> Result<AnalyticGradients, TensorError> {
+        let ws = Workspace::new();
+        let mut tape = Tape::new(&ws);
+        let input_vars: Vec<Var> = inputs.iter().map(|input| tape.leaf(input.clone())).collect();
+        let output = forward(model, &mut tape, &input_vars)?;
+        let projection = check_projection(tape.value(output).rows, tape.value(output).cols);
+        let mut grads = tape.backward(output, &projection)?;
+        let analytic = input_vars.iter().chain(&tape.params).map(|&var| grads.take(var)).collect();
+        grads.release(&ws);
+        tape.release();
+        Ok(analytic)
+    }
+
+    // Largest relative error a gradient check accepts
+    const GRADIENT_TOLERANCE: f64 = 1e-4;
+
+    // Compare analytic gradients (inputs, then parameters) against central
+    // differences of the checked loss through the tape forward. Each tensor is
+    // returned with the largest relative error over its entries.
+    fn check_gradients<M: Module<f64>>(
+        name: &str,
+        model: &mut M,
+        inputs: &[Matrix<f64>],
+        analytic: &[Option<Matrix<f64>>],
+        forward: impl Fn(&M, &mut Tape<f64>, &[Var]) -> Result<Var, TensorError>,
+    ) -> Result<Vec<(String, f64)>, TensorError> {
+        println!("Checking {} gradients", name);
+        let ws = Workspace::new();
+        let run = |model: &M, inputs: &[Matrix<f64>], projection: Option<&Matrix<f64>>| -> Result<(f64, usize, usize), TensorError> {
+            let mut tape = Tape::new(&ws);
+            let vars: Vec<Var> = inputs.iter().map(|input| tape.leaf(input.clone())).collect();
+            let output = forward(model, &mut tape, &vars)?;
+            let output = tape.value(output);
+            let result = (projection.map_or(0.0, |projection| f64::dot(&output.data, &projection.data)), output.rows, output.cols);
+            tape.release();
+            Ok(result)
+        };
+        let (_, rows, cols) = run(model, inputs, None)?;
+        let projection = check_projection(rows, cols);
+        let loss = |model: &M, inputs: &[Matrix<f64>]| run(model, inputs, Some(&projection)).map(|(value, _, _)| value);
+
+        let h = 1e-5;
+        let mut inputs = inputs.to_vec();
+        let input_count = inputs.len();
+        let names: Vec<String> = (0..input_count).map(|i| format!("input {}", i)).chain(parameter_names(model)).collect();
+        let mut errors = Vec::with_capacity(names.len());
+        for (t, (tensor_name, analytic)) in names.iter().zip(analytic).enumerate() {
+            let entry = |model: &mut M, inputs: &mut [Matrix<f64>], i: usize, value: Option<f64>| {
+                let tensor = if t < input_count { &mut inputs[t] } else { model.parameters_mut().swap_remove(t - input_count) };
+                let old = tensor.data[i];
+                if let Some(value) = value {
+                    tensor.data[i] = value;
+                }
+                (old, tensor.rows, tensor.cols)
+            };
+            let (_, rows, cols) = entry(model, &mut inputs, 0, None);
+            let mut max_error: f64 = 0.0;
+            for i in 0..rows * cols {
+                let (original, _, _) = entry(model, &mut inputs, i, Some(0.0));
+                entry(model, &mut inputs, i, Some(original + h));
+                let plus = loss(model, &inputs)?;
+                entry(model, &mut inputs, i, Some(original - h));
+                let minus = loss(model, &inputs)?;
+                entry(model, &mut inputs, i, Some(original));
+
+                let numeric = (plus - minus) / (2.0 * h);
+                let exact = analytic.as_ref().map_or(0.0, |grad| grad.data[i]);
+                max_error = max_error.max((exact - numeric).abs() / (exact.abs() + numeric.abs()).max(1e-4));
+            }
+            let ok = max_error < GRADIENT_TOLERANCE;
+            println!("  {} {} ({}x{}): max relative error {:e} [{}]", name, tensor_name, rows, cols, max_error, if ok { "ok" } else { "FAILED" });
+            errors.push((tensor_name.clone(), max_error));
+        }
+        Ok(errors)
+    }
+
+    fn parameter_names<M: Module<f64>>(module: &M) -> Vec<String> {
+        module.named_parameters().into_iter().map(|(name, _)| name).collect()
+    }
+
+    // Gradient-check fixtures: a small random input, and layers with every
+    // parameter randomized since biases and norm scales start at constants
+    const CHECK_SEQ_LEN: usize = 5;
+    const CHECK_DIM: usize = 8;
+    const CHECK_HEADS: usize = 2;
+
+    fn check_input() -> Matrix<f64> {
+        let mut input = Matrix::new(CHECK_SEQ_LEN, CHECK_DIM);
+        initialize_weights(&mut input, Init::Uniform(1.0), &mut Rng::new(8181));
+        input
+    }
+
+    fn randomized<M: Module<f64>>(mut module: M) -> M {
+        let mut rng = Rng::new(8282);
+        for param in module.parameters_mut() {
+            initialize_weights(param, Init::Uniform(1.0), &mut rng);
+        }
+        module
+    }
+
+    fn assert_within_tolerance(name: &str, errors: Vec<(String, f64)>) {
+        for (tensor, error) in errors {
+            assert!(error < GRADIENT_TOLERANCE, "{} {}: max relative error {:e}", name, tensor, error);
+        }
+    }
+
+    // Gradients from a module's own backward pass in training mode, seeded with
+    // the checked projection: the input gradient, then every parameter
+    fn module_gradients<M: Module<f64>>(module: &mut M, input: &M::Input) -> Result<(M::InputGrad, AnalyticGradients), TensorError> {
+        let ws = Workspace::new();
+        module.set_training(true);
+        module.zero_grad();
+        let output = module.forward(input, &ws)?;
+        let input_grad = module.backward(&check_projection(output.rows, output.cols), &ws)?;
+        let grads = module.named_parameters().into_iter().map(|(_, param)| Some(param.grad.clone())).collect();
+        Ok((input_grad, grads))
+    }
+
+    // The tape's gradients against central differences of the same tape forward
+    fn assert_tape_gradients<M: Module<f64>>(name: &str, mut model: M, inputs: &[Matrix<f64>], forward: impl Fn(&M, &mut Tape<f64>, &[Var]) -> Result<Var, TensorError>) {
+        let analytic = tape_gradients(&model, inputs, &forward).unwrap();
+        let errors = check_gradients(name, &mut model, inputs, &analytic, &forward).unwrap();
+        assert_within_tolerance(name, errors);
+    }
+
+    #[test]
+    fn feed_forward_tape_matches_central_differences() {
+        let feed_forward = randomized(FeedForward::new(CHECK_DIM, 6));
+        assert_tape_gradients("FeedForward tape", feed_forward, &[check_input()], |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn layer_norm_tape_matches_central_differences() {
+        let layer_norm = randomized(LayerNorm::new(CHECK_DIM));
+        assert_tape_gradients("LayerNorm tape", layer_norm, &[check_input()], |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn attention_tape_matches_central_differences() {
+        let attention = randomized(MultiHeadAttention::new(CHECK_HEADS, CHECK_DIM));
+        assert_tape_gradients("MultiHeadAttention tape", attention, &[check_input()], |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn block_tape_matches_central_differences() {
+        let block = randomized(TransformerBlock::new(CHECK_HEADS, CHECK_DIM));
+        assert_tape_gradients("TransformerBlock tape", block, &[check_input()], |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn transformer_tape_matches_central_differences() {
+        let tokens = [3, 0, 7, 10, 3];
+        let transformer = randomized(Transformer::new(11, CHECK_DIM, 2, CHECK_HEADS, InitConfig::preset("uniform").unwrap(), 12242));
+        assert_tape_gradients("Transformer tape", transformer, &[], |model, tape, _| model.forward_tape(tape, &tokens));
+    }
+
+    // A layer's own backward pass against central differences through its tape forward
+    fn assert_backward_gradients<M>(name: &str, mut layer: M, forward: impl Fn(&M, &mut Tape<f64>, &[Var]) -> Result<Var, TensorError>)
+    where
+        M: Module<f64, Input = Matrix<f64>, InputGrad = Matrix<f64>>,
+    {
+        let input = check_input();
+        let (d_input, grads) = module_gradients(&mut layer, &input).unwrap();
+        let analytic: AnalyticGradients = std::iter::once(Some(d_input)).chain(grads).collect();
+        let errors = check_gradients(name, &mut layer, std::slice::from_ref(&input), &analytic, forward).unwrap();
+        assert_within_tolerance(name, errors);
+    }
+
+    #[test]
+    fn attention_backward_matches_central_differences() {
+        let attention = randomized(MultiHeadAttention::new(CHECK_HEADS, CHECK_DIM));
+        assert_backward_gradients("MultiHeadAttention backward", attention, |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn feed_forward_backward_matches_central_differences() {
+        let feed_forward = randomized(FeedForward::new(CHECK_DIM, 6));
+        assert_backward_gradients("FeedForward backward", feed_forward, |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn layer_norm_backward_matches_central_differences() {
+        let layer_norm = randomized(LayerNorm::new(CHECK_DIM));
+        assert_backward_gradients("LayerNorm backward", layer_norm, |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn block_backward_matches_central_differences() {
+        let block = randomized(TransformerBlock::new(CHECK_HEADS, CHECK_DIM));
+        assert_backward_gradients("TransformerBlock backward", block, |layer, tape, inputs| layer.forward_tape(tape, inputs[0]));
+    }
+
+    #[test]
+    fn transformer_backward_matches_central_differences() {
+        let tokens = [3, 0, 7, 10, 3];
+        let mut transformer = randomized(Transformer::new(11, CHECK_DIM, 2, CHECK_HEADS, InitConfig::preset("uniform").unwrap(), 12242));
+        let ((), analytic) = module_gradients(&mut transformer, &tokens[..]).unwrap();
+        let errors = check_gradients("Transformer backward", &mut transformer, &[], &analytic, |model, tape, _| model.forward_tape(tape, &tokens)).unwrap();
+        assert_within_tolerance("Transformer backward", errors);
+    }
+
+    // Inference runs the training graph, minus the state kept for backward
+    #[test]
+    fn inference_forward_matches_training_and_keeps_no_backward_state() {
+        let tokens = [3, 0, 7, 10, 3];
+        let mut transformer = randomized(Transformer::<f64>::new(11, CHECK_DIM, 2, CHECK_HEADS, InitConfig::preset("uniform").unwrap(), 12242));
+        let ws = Workspace::new();
+        transformer.set_training(true);
+        let training = transformer.forward(&tokens, &ws).unwrap();
+        let inference = transformer.infer(&tokens).unwrap();
+        assert!(transformer.is_training());
+        assert_eq!(training.data, inference.data);
+        assert!(transformer.embedding.cache.is_none());
+        assert!(transformer.blocks.iter().all(|block| block.attention.cache.is_none() && block.feed_forward.cache.is_none()));
+        assert!(transformer.backward(&inference, &ws).is_err());
+    }
+
+    // A few steps of each optimizer on a fixed gradient against the update worked out by hand
+    #[test]
+    fn optimizers_match_hand_computed_updates() {
+        let (weights, grad, lr) = ([1.0, -2.0, 0.5], [0.5, -1.0, 2.0], 0.1);
+        let sign = |g: f64| g.signum();
+        let cases = vec![
+            ("sgd", Box::new(Sgd::new(lr, 0.0, false, default_param_groups(0.0))) as Box<dyn Optimizer<f64>>, "w", 2, (0..3).map(|i| weights[i] - 2.0 * lr * grad[i]).collect::<Vec<f64>>()),
+            ("momentum", Box::new(Sgd::new(lr, 0.9, false, default_param_groups(0.0))), "w", 2, (0..3).map(|i| weights[i] - 2.9 * lr * grad[i]).collect()),
+            ("nesterov", Box::new(Sgd::new(lr, 0.9, true, default_param_groups(0.0))), "w", 2, (0..3).map(|i| weights[i] - 4.61 * lr * grad[i]).collect()),
+            ("adam", Box::new(Adam::new(lr, (0.9, 0.999), 1e-8, false, default_param_groups(0.0))), "w", 1, (0..3).map(|i| weights[i] - lr * sign(grad[i])).collect()),
+            ("adamw decay", Box::new(Adam::new(lr, (0.9, 0.999), 1e-8, true, default_param_groups(0.1))), "w", 1, (0..3).map(|i| weights[i] - lr * (sign(grad[i]) + 0.1 * weights[i])).collect()),
+            ("adamw no_decay", Box::new(Adam::new(lr, (0.9, 0.999), 1e-8, true, default_param_groups(0.1))), "b1", 1, (0..3).map(|i| weights[i] - lr * sign(grad[i])).collect()),
+        ];
+        for (case, mut optimizer, name, steps, expected) in cases {
+            let mut param = Param::new(1, 3);
+            param.value.data.copy_from_slice(&weights);
+            param.grad.data.copy_from_slice(&grad);
+            for _ in 0..steps {
+                optimizer.step(vec![(name.to_string(), &mut param)]).unwrap();
+            }
+            let max_err = param.value.data.iter().zip(&expected).map(|(g, e)| (g - e).abs()).fold(0.0, f64::max);
+            assert!(max_err < 1e-6, "{}: max abs error {:e}", case, max_err);
+        }
+    }
+
+    #[test]
+    fn norm_and_bias_parameters_skip_weight_decay() {
+        for name in ["blocks.0.norm1.gamma", "blocks.2.norm2.beta", "output_layer.b1", "blocks.1.feed_forward.b2"] {
+            assert!(is_norm_or_bias(name), "{} should skip weight decay", name);
+        }
+        for name in ["embedding.embeddings", "blocks.0.attention.w_q", "output_layer.w2", "blocks.1.feed_forward.w1"] {
+            assert!(!is_norm_or_bias(name), "{} should be decayed", name);
+        }
+    }
+
+    // Each schedule against values worked out by hand
+    #[test]
+    fn schedules_match_hand_computed_rates() {
+        let cases: [(&str, &[(usize, f64)]); 5] = [
+            ("warmup(4,constant(1))", &[(0, 0.25), (2, 0.75), (3, 1.0), (9, 1.0)]),
+            ("cosine(1,0.1,10)", &[(0, 1.0), (5, 0.55), (10, 0.1), (20, 0.1)]),
+            ("step(1,0.5,3)", &[(2, 1.0), (3, 0.5), (6, 0.25)]),
+            ("inverse_sqrt(1,4)", &[(1, 0.5), (3, 1.0), (15, 0.5)]),
+            ("warmup(2,step(0.5,0.1,4))", &[(0, 0.25), (1, 0.5), (5, 0.5), (6, 0.05)]),
+        ];
+        for (spec, expected) in cases {
+            let scheduler = parse_scheduler(spec).unwrap();
+            assert_eq!(scheduler.spec(), spec);
+            for &(step, rate) in expected {
+                assert!((scheduler.rate(step) - rate).abs() < 1e-12, "{} at step {}: {} != {}", spec, step, scheduler.rate(step), rate);
+            }
+        }
+    }
+
+    #[test]
+    fn saved_schedule_resumes_on_the_same_curve() {
+        let mut schedule = LrSchedule::default_for(1e-3, 500);
+        for _ in 0..37 {
+            schedule.next_rate();
+        }
+        let mut resumed = LrSchedule::load(&schedule.save()).unwrap();
+        assert_eq!(resumed.save(), schedule.save());
+        for _ in 0..100 {
+            assert_eq!(resumed.next_rate(), schedule.next_rate());
+        }
+    }
+
+    #[test]
+    fn malformed_schedule_specs_are_rejected() {
+        for spec in ["cosine(1,2)", "linear(1)", "warmup(0,constant(1))", "step(1,0.5,3"] {
+            assert!(parse_scheduler(spec).is_err(), "{} should not parse", spec);
+        }
+    }
+
+    // Gradients accumulated over several sequences must equal the sum of each
+    // sequence's gradients taken on its own
+    #[test]
+    fn gradients_accumulate_across_a_batch() {
+        let mut transformer = Transformer::<f64>::new(11, 8, 1, 2, InitConfig::preset("uniform").unwrap(), 12242);
+        let sequences: [(&[usize], &[usize]); 2] = [(&[3, 0, 7, 10], &[0, 7, 10, 3]), (&[5, 5, 1, 9], &[5, 1, 9, 2])];
+        let mut grads = |batch: &[(&[usize], &[usize])]| -> Vec<f64> {
+            transformer.zero_grad();
+            for &(input, target) in batch {
+                transformer.accumulate_gradients(input, target).unwrap();
+            }
+            transformer.named_parameters().into_iter().flat_map(|(_, param)| param.grad.data.clone()).collect()
+        };
+        let first = grads(&sequences[..1]);
+        let second = grads(&sequences[1..]);
+        let both = grads(&sequences);
+        let max_err = both.iter().zip(first.iter().zip(&second)).map(|(b, (x, y))| (b - (x + y)).abs() / (x + y).abs().max(1.0)).fold(0.0, f64::max);
+        assert!(max_err < 1e-12, "accumulated vs summed gradients: max relative error {:e}", max_err);
+    }
+
+    // A sequence that fails part way hands its buffers back, so skipping bad
+    // batches doesn't grow the workspace's in-use bytes
+    #[test]
+    fn failed_sequences_return_their_buffers() {
+        let mut transformer = Transformer::<f64>::new(11, 8, 1, 2, InitConfig::preset("uniform").unwrap(), 12242);
+        let in_use_after = |transformer: &mut Transformer<f64>, input: &[usize], target: &[usize]| {
+            assert!(transformer.accumulate_gradients(input, target).is_err());
+            transformer.workspace.state.lock().unwrap().in_use_bytes
+        };
+        let baseline = in_use_after(&mut transformer, &[3, 0, 7], &[0, 7, 11]);
+        for _ in 0..3 {
+            assert_eq!(in_use_after(&mut transformer, &[3, 0, 7], &[0, 7, 11]), baseline);
+            assert_eq!(in_use_after(&mut transformer, &[3, 0, 70], &[0, 7, 10]), baseline);
+        }
+        transformer.output_layer.b2.value.set(0, 0, f64::NAN);
+        for _ in 0..3 {
+            assert_eq!(in_use_after(&mut transformer, &[3, 0, 7], &[0, 7, 10]), baseline);
+        }
+    }
+
+    // Clip two parameters' gradients, returning the result and every gradient afterwards
+    fn clip(clipping: GradientClipping, grads: [[f64; 2]; 2]) -> (Result<f64, TensorError>, Vec<f64>) {
+        let mut owned: Vec<(String, Param<f64>)> = grads.iter().enumerate().map(|(i, grad)| {
+            let mut param = Param::new(1, 2);
+            param.grad.data.copy_from_slice(grad);
+            (format!("p{}", i), param)
+        }).collect();
+        let mut borrowed: Vec<(String, &mut Param<f64>)> = owned.iter_mut().map(|(name, param)| (name.clone(), param)).collect();
+        let result = clipping.apply(&mut borrowed);
+        (result, owned.iter().flat_map(|(_, param)| param.grad.data.clone()).collect())
+    }
+
+    #[test]
+    fn gradients_clip_to_global_norm() {
+        let (norm, clipped) = clip(GradientClipping { max_norm: Some(6.5), max_value: None }, [[3.0, 4.0], [0.0, 12.0]]);
+        assert_eq!(norm.unwrap(), 13.0);
+        assert_eq!(clipped, vec![1.5, 2.0, 0.0, 6.0]);
+    }
+
+    #[test]
+    fn gradients_clip_by_value() {
+        let (_, clipped) = clip(GradientClipping { max_norm: None, max_value: Some(5.0) }, [[3.0, -7.0], [0.0, 12.0]]);
+        assert_eq!(clipped, vec![3.0, -5.0, 0.0, 5.0]);
+    }
+
+    // A NaN gradient is reported against its parameter before anything is clipped
+    #[test]
+    fn non_finite_gradient_names_its_parameter() {
+        let (result, clipped) = clip(GradientClipping { max_norm: Some(1.0), max_value: Some(1.0) }, [[3.0, 4.0], [0.0, f64::NAN]]);
+        assert!(matches!(&result, Err(TensorError::NonFiniteGradient { param, index }) if param == "p1" && index == &vec![0, 1]), "{:?}", result.err().map(|e| e.to_string()));
+        assert_eq!(clipped[..3], [3.0, 4.0, 0.0]);
+    }
+
+    fn mean_and_std(data: &[f64]) -> (f64, f64) {
+        let mean = data.iter().sum::<f64>() / data.len() as f64;
+        (mean, (data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / data.len() as f64).sqrt())
+    }
+
+    fn assert_close(name: &str, actual: f64, expected: f64) {
+        assert!((actual / expected - 1.0).abs() < 0.03, "{}: {:.5}, expected {:.5}", name, actual, expected);
+    }
+
+    // Sample statistics of each initialization scheme against the variance it targets
+    #[test]
+    fn initializations_match_target_statistics() {
+        let mut rng = Rng::new(4242);
+        let gaussian: Vec<f64> = (0..100_000).map(|_| rng.next_gaussian()).collect();
+        let (mean, std) = mean_and_std(&gaussian);
+        assert!(mean.abs() < 0.02, "gaussian mean {:.4}", mean);
+        assert_close("gaussian std", std, 1.0);
+
+        let (fan_in, fan_out) = (256, 512);
+        let mut sample = |init: Init| {
+            let mut matrix = Matrix::<f64>::new(fan_in, fan_out);
+            initialize_weights(&mut matrix, init, &mut rng);
+            matrix.data
+        };
+        let xavier = sample(Init::Xavier);
+        let limit = (6.0 / (fan_in + fan_out) as f64).sqrt();
+        assert!(xavier.iter().all(|x| x.abs() <= limit));
+        assert_close("xavier std", mean_and_std(&xavier).1, limit / 3f64.sqrt());
+
+        assert_close("kaiming std", mean_and_std(&sample(Init::Kaiming)).1, (2.0 / fan_in as f64).sqrt());
+
+        // A standard normal cut at two standard deviations has std 0.8796
+        let truncated = sample(Init::TruncatedNormal(0.02));
+        assert!(truncated.iter().all(|x| x.abs() <= 0.04));
+        assert_close("truncated normal std", mean_and_std(&truncated).1, 0.02 * 0.8796);
+    }
+
+    // GPT-2 scales the projections into the residual stream by 1 / sqrt(2 * blocks)
+    #[test]
+    fn gpt2_init_scales_residual_projections() {
+        let num_blocks = 4;
+        let transformer = Transformer::<f64>::new(16, 64, num_blocks, 2, InitConfig::preset("gpt2").unwrap(), 12242);
+        let block = &transformer.blocks[num_blocks - 1];
+        let ratio = mean_and_std(&block.attention.w_o.value.data).1 / mean_and_std(&block.attention.w_q.value.data).1;
+        assert!((ratio * (2.0 * num_blocks as f64).sqrt() - 1.0).abs() < 0.1, "residual projection std ratio {:.4}", ratio);
+    }
+
+    #[test]
+    fn xoshiro_matches_reference_output() {
+        let mut reference = Rng { state: [1, 2, 3, 4] };
+        let outputs: Vec<u64> = (0..4).map(|_| reference.next()).collect();
+        assert_eq!(outputs, [11520, 0, 1509978240, 1215971899390074240]);
+    }
+
+    fn draws(mut rng: Rng) -> Vec<u64> {
+        (0..8).map(|_| rng.next()).collect()
+    }
+
+    #[test]
+    fn named_and_split_streams_are_reproducible_and_distinct() {
+        assert_eq!(draws(Rng::stream(7, "init")), draws(Rng::stream(7, "init")));
+        assert_ne!(draws(Rng::stream(7, "init")), draws(Rng::stream(7, "sampling")));
+        assert_ne!(draws(Rng::stream(7, "init")), draws(Rng::stream(8, "init")));
+        let mut parent = Rng::stream(7, "init");
+        let child = parent.split();
+        assert_eq!(draws(child), draws(Rng::stream(7, "init")));
+        assert_ne!(draws(parent), draws(Rng::stream(7, "init")));
+    }
+
+    // Seeds that differ by the XOR of two name hashes must not swap streams
+    #[test]
+    fn streams_do_not_collide_across_seeds() {
+        let seed = 7 ^ fnv1a("init") ^ fnv1a("sampling");
+        assert_ne!(draws(Rng::stream(7, "init")), draws(Rng::stream(seed, "sampling")));
+    }
+
+    #[test]
+    fn distributions_match_their_frequencies() {
+        let mut rng = Rng::stream(7, "check");
+        let n = 100_000;
+        let mut counts = [0usize; 3];
+        (0..n).for_each(|_| counts[rng.categorical(&[1.0, 0.0, 3.0])] += 1);
+        assert_eq!(counts[1], 0);
+        assert!((counts[0] as f64 / n as f64 - 0.25).abs() < 0.01, "categorical counts {:?}", counts);
+
+        let heads = (0..n).filter(|_| rng.bernoulli(0.3)).count() as f64 / n as f64;
+        assert!((heads - 0.3).abs() < 0.01, "bernoulli(0.3) frequency {:.4}", heads);
+
+        let uniform: Vec<f64> = (0..n).map(|_| rng.uniform(-2.0, 4.0)).collect();
+        let mean = uniform.iter().sum::<f64>() / n as f64;
+        assert!(uniform.iter().all(|&x| (-2.0..4.0).contains(&x)));
+        assert!((mean - 1.0).abs() < 0.03, "uniform(-2, 4) mean {:.4}", mean);
+    }
+
+    // Encoding after fit must leave the vocabulary and every id unchanged, even
+    // for text full of words the tokenizer has never seen
+    #[test]
+    fn encoding_after_fit_leaves_vocabulary_unchanged() {
+        let mut tokenizer = WordTokenizer::new(VocabBuilder::default());
+        tokenizer.fit("the cat sat on the mat the cat sat on the mat the cat sat on a mat");
+        let before = tokenizer.encode("the cat sat on the mat");
+        let vocab_size = tokenizer.vocab_size();
+        let unseen = tokenizer.encode("The dog sat on a log");
+        assert_eq!(tokenizer.vocab_size(), vocab_size);
+        assert_eq!(tokenizer.encode("the cat sat on the mat"), before);
+        assert_eq!(unseen, vec![before[0], 0, before[2], before[3], 0, 0]);
+        assert_eq!(tokenizer.decode(&unseen), "the <UNK> sat on <UNK> <UNK>");
+    }
+
+    const BPE_CORPUS: &str = "low lower lowest newer newest wider widest low low lower newest";
+
+    // Learned merges shorten repeated words, and training twice gives the same merges
+    #[test]
+    fn bpe_learns_merges_deterministically() {
+        let bpe = BpeTokenizer::train(BPE_CORPUS, 280);
+        assert!(bpe.vocab_size() > 256);
+        assert!(bpe.encode(BPE_CORPUS).len() < BPE_CORPUS.len());
+        assert_eq!(BpeTokenizer::train(BPE_CORPUS, 280).tokens, bpe.tokens);
+    }
+
+    // Unseen text, including non-ASCII and odd whitespace, round-trips through byte fallback
+    #[test]
+    fn bpe_round_trips_any_text() {
+        let bpe = BpeTokenizer::train(BPE_CORPUS, 280);
+        let text = "  Naïve lowest café 🦀\n\tzzz  ";
+        let ids = bpe.encode(text);
+        assert_eq!(bpe.decode(&ids), text);
+        assert!(ids.iter().all(|&id| id < bpe.vocab_size()));
+    }
+
+    fn specials(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| token.to_string()).collect()
+    }
+
+    // Most frequent first, ties alphabetical, after <UNK> and the specials
+    #[test]
+    fn vocabulary_keeps_most_frequent_words_and_reports_coverage() {
+        let counts: HashMap<String, usize> = [("b", 2), ("a", 2), ("c", 5), ("d", 1)].iter().map(|&(w, c)| (w.to_string(), c)).collect();
+        let (vocab, coverage) = VocabBuilder::new(2, 4, specials(&["<BOS>"])).build(&counts);
+        assert_eq!(vocab, ["<UNK>", "<BOS>", "c", "a"]);
+        assert_eq!(coverage, VocabCoverage { kept_words: 2, unique_words: 4, covered_tokens: 7, total_tokens: 10 });
+    }
+
+    #[test]
+    fn repeated_specials_are_dropped_when_the_builder_is_made() {
+        let builder = VocabBuilder::new(1, 100, specials(&["<BOS>", "<UNK>", "<EOS>", "<BOS>"]));
+        assert_eq!(builder.specials, ["<BOS>", "<EOS>"]);
+
+        let mut tokenizer = WordTokenizer::new(builder);
+        tokenizer.fit("one two two");
+        let text = tokenizer.save();
+        let loaded = load_tokenizer(&text).unwrap();
+        assert_eq!(loaded.save(), text);
+        assert_eq!(loaded.encode("<EOS> two"), tokenizer.encode("<EOS> two"));
+    }
+
+    // Specials are matched before lowercasing; other words still fold case
+    #[test]
+    fn special_tokens_keep_their_ids_in_text() {
+        let mut tokenizer = WordTokenizer::new(VocabBuilder::new(1, 100, specials(&["<BOS>", "<EOS>"])));
+        tokenizer.fit("<BOS> The cat <EOS>");
+        assert_eq!(tokenizer.encode("<BOS> the CAT <EOS>"), vec![1, tokenizer.encode("the")[0], tokenizer.encode("cat")[0], 2]);
+        assert!(!tokenizer.ids.contains_key("<bos>"));
+        assert_eq!(tokenizer.encode("<bos>"), vec![0]);
+    }
+
+    // Half a million words drawn from 5000, about 3 MB
+    fn large_corpus() -> String {
+        let mut rng = Rng::stream(7, "check");
+        (0..500_000).map(|_| format!("w{} ", rng.next() % 5000)).collect()
+    }
+
+    // Special token ids are fixed and cached, and a multi-megabyte corpus
+    // fits and encodes through the hash map lookups
+    #[test]
+    fn word_tokenizer_fits_and_encodes_a_large_corpus() {
+        let corpus = large_corpus();
+        let start = std::time::Instant::now();
+        let mut tokenizer = WordTokenizer::new(VocabBuilder::new(3, 10000, specials(&["<BOS>", "<EOS>"])));
+        tokenizer.fit(&corpus);
+        let ids = tokenizer.encode(&corpus);
+        println!("fit and encode {:.1} MB in {:.3}s", corpus.len() as f64 / 1e6, start.elapsed().as_secs_f64());
+        assert_eq!(ids.len(), 500_000);
+        assert_eq!(tokenizer.decode(&ids[..3]), corpus.split_whitespace().take(3).collect::<Vec<_>>().join(" "));
+        assert_eq!(tokenizer.unknown_id(), Some(0));
+        assert_eq!(tokenizer.special_id("<BOS>"), Some(1));
+        assert_eq!(tokenizer.special_id("<EOS>"), Some(2));
+    }
+
+    // A saved tokenizer loads back with the same ids and saves to the same text
+    #[test]
+    fn saved_tokenizers_load_with_the_same_ids() {
+        let corpus = large_corpus();
+        let mut word = WordTokenizer::new(VocabBuilder::new(3, 10000, specials(&["<BOS>", "<EOS>"])));
+        word.fit(&corpus);
+        let bpe = BpeTokenizer::train(BPE_CORPUS, 280);
+        let sample = "The lowest newer wider café mat sat <BOS>";
+        let saved: [(&str, &dyn Tokenizer); 2] = [("word", &word), ("bpe", &bpe)];
+        for (name, original) in saved {
+            let text = original.save();
+            let loaded = load_tokenizer(&text).unwrap_or_else(|e| panic!("{} tokenizer: {}", name, e));
+            assert_eq!(loaded.save(), text, "{} tokenizer", name);
+            assert_eq!(loaded.encode(sample), original.encode(sample), "{} tokenizer", name);
+            assert_eq!(loaded.encode(&corpus[..1000]), original.encode(&corpus[..1000]), "{} tokenizer", name);
+            assert_eq!(loaded.unknown_id(), original.unknown_id(), "{} tokenizer", name);
+        }
+    }
+
+    #[test]
+    fn damaged_tokenizer_files_are_rejected() {
+        let bpe_text = BpeTokenizer::train(BPE_CORPUS, 280).save();
+        let mut word = WordTokenizer::new(VocabBuilder::new(1, 100, specials(&["<BOS>", "<EOS>"])));
+        word.fit("a b c");
+        // A huge count must end at the end of the file, not in a failed allocation
+        let recount = |text: &str, field: &str, count: &str| {
+            text.lines()
+                .map(|line| if line.split_whitespace().next() == Some(field) { format!("{} {}", field, count) } else { line.to_string() })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let damaged = [
+            bpe_text.replace("v1", "v2"),
+            bpe_text.replace("kind bpe", "kind wordpiece"),
+            bpe_text.replacen("\n108 111\n", "\n108 999\n", 1),
+            bpe_text.lines().take(6).collect::<Vec<_>>().join("\n"),
+            word.save().replace("specials <BOS> <EOS>", "specials <EOS> <BOS>"),
+            format!("{}extra\n", bpe_text),
+            recount(&bpe_text, "merges", "18446744073709551615"),
+            recount(&word.save(), "tokens", "100000000000000"),
+        ];
+        for text in &damaged {
+            assert!(load_tokenizer(text).is_err(), "loaded a damaged tokenizer:\n{}", text);
+        }
+    }
+
+    // Header, kind, normalize and merge count take lines 1-4, so merge i is on line 5 + i
+    #[test]
+    fn repeated_bpe_merge_is_rejected_with_its_line() {
+        let text = format!("{}\nkind bpe\nnormalize none\nmerges 3\n108 111\n119 101\n108 111\n", TOKENIZER_FORMAT);
+        let error = load_tokenizer(&text).err().expect("repeated merge loaded");
+        assert_eq!(error.input, "108 111");
+        assert!(error.reason.contains("line 7"), "{}", error);
+    }
 }